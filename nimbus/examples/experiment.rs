@@ -64,6 +64,14 @@ fn main() -> Result<()> {
             SubCommand::with_name("show-experiments")
                 .about("Show all experiments, followed by the enrolled experiments"),
         )
+        .subcommand(
+            SubCommand::with_name("show-enrollment-states")
+                .about("Show the state of the enrollment in every experiment, and why"),
+        )
+        .subcommand(
+            SubCommand::with_name("show-rejected-experiments")
+                .about("Show the experiments that were rejected when fetching, and why"),
+        )
         .subcommand(
             SubCommand::with_name("fetch-experiments")
             .about("Fetch experiments from the server. Subsequent calls to apply-pending-experiments will change enrolments."),
@@ -185,6 +193,10 @@ fn main() -> Result<()> {
             });
     log::info!("Collection name is {}", collection_name);
 
+    let trust_root = config
+        .get("trust_root")
+        .map(|v| v.as_str().unwrap().to_string());
+
     let temp_dir = std::env::temp_dir();
     let db_path_default = temp_dir.to_str().unwrap();
     let db_path = matches
@@ -200,12 +212,15 @@ fn main() -> Result<()> {
         server_url: server_url.to_string(),
         bucket_name: bucket_name.to_string(),
         collection_name: collection_name.to_string(),
+        trust_root,
+        retry_policy: None,
+        additional_sources: vec![],
     };
 
     let aru = AvailableRandomizationUnits::with_client_id(&client_id);
 
     // Here we initialize our main `NimbusClient` struct
-    let nimbus_client = NimbusClient::new(context.clone(), "", Some(config), aru)?;
+    let nimbus_client = NimbusClient::new(context.clone(), "", Some(config), aru, None)?;
 
     // Explicitly update experiments at least once for init purposes
     nimbus_client.fetch_experiments()?;
@@ -233,6 +248,40 @@ fn main() -> Result<()> {
                     )
                 });
         }
+        ("show-enrollment-states", _) => {
+            println!("======================================");
+            println!("Printing enrollment states");
+            for state in nimbus_client.get_enrollment_states()? {
+                println!(
+                    "Experiment: {}, state: {:?}, branch: {}, reason: {}",
+                    state.experiment_slug,
+                    state.state,
+                    state.branch_slug.as_deref().unwrap_or("<none>"),
+                    state.reason.as_deref().unwrap_or("<none>")
+                );
+            }
+        }
+        ("show-rejected-experiments", _) => {
+            println!("======================================");
+            println!("Printing rejected experiments");
+            for rejected in nimbus_client.get_rejected_experiments()? {
+                println!(
+                    "Rejected experiment: {} (id {}){}, reason: {:?}{}, {}",
+                    rejected.slug.as_deref().unwrap_or("<no slug>"),
+                    rejected.id.as_deref().unwrap_or("<no id>"),
+                    rejected
+                        .source
+                        .map(|source| format!(" in {}", source))
+                        .unwrap_or_default(),
+                    rejected.reason,
+                    rejected
+                        .field_path
+                        .map(|path| format!(" at {}", path))
+                        .unwrap_or_default(),
+                    rejected.message
+                );
+            }
+        }
         ("fetch-experiments", _) => {
             println!("======================================");
             println!("Fetching experiments");
@@ -291,7 +340,8 @@ fn main() -> Result<()> {
                 let uuid = uuid::Uuid::new_v4();
                 let mut num_of_experiments_enrolled = 0;
                 for exp in &all_experiments {
-                    let enr = nimbus::evaluate_enrollment(&uuid, &aru, &context.clone(), &exp)?;
+                    let enr =
+                        nimbus::evaluate_enrollment(&uuid, &aru, &context.clone().into(), &exp)?;
                     if enr.status.is_enrolled() {
                         num_of_experiments_enrolled += 1;
                         if num_of_experiments_enrolled >= num {
@@ -345,7 +395,8 @@ fn main() -> Result<()> {
                 // options.
                 let uuid = uuid::Uuid::new_v4();
                 let aru = AvailableRandomizationUnits::with_client_id(&client_id);
-                let enrollment = nimbus::evaluate_enrollment(&uuid, &aru, &context.clone(), &exp)?;
+                let enrollment =
+                    nimbus::evaluate_enrollment(&uuid, &aru, &context.clone().into(), &exp)?;
                 let key = match enrollment.status.clone() {
                     EnrollmentStatus::Enrolled { .. } => "Enrolled",
                     EnrollmentStatus::NotEnrolled { .. } => "NotEnrolled",
@@ -5,14 +5,21 @@
 //! A SettingsClient that uses the file-system. Used for developer ergonomics
 //! (eg, for testing against experiments which are not deployed anywhere) and
 //! for tests.
+//!
+//! The path can be a directory of `.json` files, or a single file. Each file
+//! can hold one experiment, or be a Remote Settings `/records` response (ie,
+//! `{"data": [...]}`) with any number of them. Either way, the experiments
+//! are checked just as if they had come from the server.
 
+use super::http_client::parse_experiment;
+use super::{FetchedExperiments, RejectedExperiment, RejectionReason};
 use crate::error::Result;
-use crate::Experiment;
+use crate::RemoteMetadata;
 use crate::SettingsClient;
+use serde_json::Value;
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::BufReader;
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
 pub struct FileSystemClient {
     path: PathBuf,
@@ -24,38 +31,228 @@ impl FileSystemClient {
             path: path.as_ref().into(),
         })
     }
-}
 
-impl SettingsClient for FileSystemClient {
-    fn get_experiments_metadata(&self) -> Result<String> {
-        unimplemented!();
-    }
-
-    fn fetch_experiments(&self) -> Result<Vec<Experiment>> {
-        log::info!("reading experiments in {}", self.path.display());
-        let mut res = Vec::new();
+    fn json_files(&self) -> Result<Vec<PathBuf>> {
+        if self.path.is_file() {
+            return Ok(vec![self.path.clone()]);
+        }
         // Skip directories and non .json files (eg, READMEs)
         let json_ext = Some(OsStr::new("json"));
-        let filenames = self
+        let mut files: Vec<_> = self
             .path
             .read_dir()?
             .filter_map(Result::ok)
             .map(|c| c.path())
-            .filter(|f| f.is_file() && f.extension() == json_ext);
-        for child_path in filenames {
-            let file = File::open(child_path.clone())?;
-            let reader = BufReader::new(file);
-            match serde_json::from_reader::<_, Experiment>(reader) {
-                Ok(exp) => res.push(exp),
+            .filter(|f| f.is_file() && f.extension() == json_ext)
+            .collect();
+        // Sort so that the experiments are in a stable order.
+        files.sort();
+        Ok(files)
+    }
+
+    /// Reads the experiments from all the files, along with the records we
+    /// couldn't use. A file we can't read at all is rejected as a whole.
+    pub fn read_experiments(&self) -> Result<FetchedExperiments> {
+        let mut fetched = FetchedExperiments::default();
+        for path in self.json_files()? {
+            let source = path.display().to_string();
+            let value = match std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|s| serde_json::from_str::<Value>(&s).map_err(|e| e.to_string()))
+            {
+                Ok(value) => value,
                 Err(e) => {
-                    log::warn!(
-                        "Malformed experiment found! File {},  Error: {}",
-                        child_path.display(),
-                        e
-                    );
+                    log::warn!("Unreadable experiments file {}: {}", source, e);
+                    fetched
+                        .rejected
+                        .push(rejected_file(source, RejectionReason::Unreadable, e));
+                    continue;
+                }
+            };
+            let records: Vec<&Value> = match value.get("data") {
+                Some(Value::Array(records)) => records.iter().collect(),
+                Some(_) => {
+                    log::warn!("Malformed experiments file {}", source);
+                    fetched.rejected.push(RejectedExperiment {
+                        field_path: Some("data".to_string()),
+                        ..rejected_file(
+                            source,
+                            RejectionReason::Malformed,
+                            "`data` is not a list".to_string(),
+                        )
+                    });
+                    continue;
+                }
+                None => vec![&value],
+            };
+            // `parse_experiment()` logs the records it rejects.
+            for record in records {
+                match parse_experiment(record) {
+                    Ok(exp) => fetched.experiments.push(exp),
+                    Err(e) => fetched.rejected.push(RejectedExperiment {
+                        source: Some(source.clone()),
+                        ..e
+                    }),
                 }
             }
         }
-        Ok(res)
+        Ok(fetched)
+    }
+}
+
+fn rejected_file(source: String, reason: RejectionReason, message: String) -> RejectedExperiment {
+    RejectedExperiment {
+        id: None,
+        slug: None,
+        source: Some(source),
+        reason,
+        field_path: None,
+        message,
+    }
+}
+
+impl SettingsClient for FileSystemClient {
+    // The files aren't signed, and their last modified time is that of the
+    // most recently modified file.
+    fn get_experiments_metadata(&self) -> Result<RemoteMetadata> {
+        let filenames = self.json_files()?;
+        let last_modified = filenames
+            .iter()
+            .filter_map(|f| f.metadata().and_then(|m| m.modified()).ok())
+            .filter_map(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64)
+            .max();
+        let fetched = self.read_experiments()?;
+        Ok(RemoteMetadata {
+            source: self.path.display().to_string(),
+            last_modified,
+            record_count: (fetched.experiments.len() + fetched.rejected.len()) as u64,
+            ..Default::default()
+        })
+    }
+
+    fn fetch_experiments(&self) -> Result<FetchedExperiments> {
+        log::info!("reading experiments in {}", self.path.display());
+        self.read_experiments()
+    }
+
+    #[cfg(feature = "fs-watch")]
+    fn local_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Experiment;
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    fn experiment(slug: &str, schema_version: &str) -> Value {
+        json!({
+            "schemaVersion": schema_version,
+            "slug": slug,
+            "application": "fenix",
+            "userFacingName": "",
+            "userFacingDescription": "",
+            "isEnrollmentPaused": false,
+            "bucketConfig": {
+                "randomizationUnit": "nimbus_id",
+                "namespace": slug,
+                "start": 0,
+                "count": 10000,
+                "total": 10000,
+            },
+            "probeSets": [],
+            "branches": [{"slug": "control", "ratio": 1}],
+            "proposedEnrollment": 7,
+            "id": slug,
+        })
+    }
+
+    fn slugs(experiments: &[Experiment]) -> Vec<&str> {
+        experiments.iter().map(|e| e.slug.as_str()).collect()
+    }
+
+    #[test]
+    fn test_read_directory() -> Result<()> {
+        let tmp_dir = TempDir::new("test_fs_client_read_directory")?;
+        let dir = tmp_dir.path();
+        std::fs::write(dir.join("a.json"), experiment("a", "1.0.0").to_string())?;
+        std::fs::write(
+            dir.join("b.json"),
+            json!({"data": [experiment("b", "1.0.0"), experiment("c", "2.0.0")]}).to_string(),
+        )?;
+        std::fs::write(dir.join("d.json"), experiment("d", "2.0.0").to_string())?;
+        std::fs::write(dir.join("e.json"), "not json")?;
+        std::fs::write(dir.join("f.json"), json!({"data": {}}).to_string())?;
+        std::fs::write(dir.join("README.md"), "not an experiment")?;
+
+        let client = FileSystemClient::new(dir)?;
+        let fetched = client.read_experiments()?;
+        assert_eq!(slugs(&fetched.experiments), vec!["a", "b"]);
+        let source = |name: &str| Some(dir.join(name).display().to_string());
+        let rejected: Vec<_> = fetched
+            .rejected
+            .iter()
+            .map(|r| (r.source.clone(), r.slug.clone(), r.reason))
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![
+                (
+                    source("b.json"),
+                    Some("c".to_string()),
+                    RejectionReason::SchemaVersionMismatch
+                ),
+                (
+                    source("d.json"),
+                    Some("d".to_string()),
+                    RejectionReason::SchemaVersionMismatch
+                ),
+                (source("e.json"), None, RejectionReason::Unreadable),
+                (source("f.json"), None, RejectionReason::Malformed),
+            ]
+        );
+        assert_eq!(fetched.rejected[3].field_path.as_deref(), Some("data"));
+
+        assert_eq!(
+            slugs(&client.fetch_experiments()?.experiments),
+            vec!["a", "b"]
+        );
+        assert_eq!(client.get_experiments_metadata()?.record_count, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_file() -> Result<()> {
+        let tmp_dir = TempDir::new("test_fs_client_read_file")?;
+        let path = tmp_dir.path().join("records.json");
+        let mut malformed = experiment("c", "1.0.0");
+        malformed.as_object_mut().unwrap().remove("branches");
+        std::fs::write(
+            &path,
+            json!({"data": [experiment("a", "1.0.0"), experiment("b", "1.0.0"), malformed]})
+                .to_string(),
+        )?;
+        // Other files next to it are ignored.
+        std::fs::write(
+            tmp_dir.path().join("d.json"),
+            experiment("d", "1.0.0").to_string(),
+        )?;
+
+        let client = FileSystemClient::new(&path)?;
+        let fetched = client.read_experiments()?;
+        assert_eq!(slugs(&fetched.experiments), vec!["a", "b"]);
+        assert_eq!(fetched.rejected.len(), 1);
+        assert_eq!(fetched.rejected[0].slug.as_deref(), Some("c"));
+        assert_eq!(fetched.rejected[0].reason, RejectionReason::Malformed);
+
+        let metadata = client.get_experiments_metadata()?;
+        assert_eq!(metadata.source, path.display().to_string());
+        assert_eq!(metadata.record_count, 3);
+        assert!(metadata.last_modified.is_some());
+        Ok(())
     }
 }
@@ -0,0 +1,77 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Watches the experiments read by the FileSystemClient for changes, so that
+//! when developing experiments locally, edits are picked up without having to
+//! fetch them again by hand. This uses inotify on Linux, and the equivalent
+//! elsewhere.
+
+use super::fs_client::FileSystemClient;
+use super::FetchedExperiments;
+use crate::error::{Error, Result};
+use crate::SettingsClient;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+// Editors often write a file in several steps, so we wait for the changes to
+// settle before reading the experiments.
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(500);
+
+/// Watches the experiments on the file-system until it's dropped.
+pub struct ExperimentsWatcher {
+    // Dropping the watcher closes its channel, which stops the thread.
+    _watcher: RecommendedWatcher,
+}
+
+/// Starts watching the experiments at `path`, a directory or a single file.
+/// Whenever they change, they are read and passed to `on_change` on a
+/// background thread.
+pub(crate) fn watch_experiments<F>(path: PathBuf, on_change: F) -> Result<ExperimentsWatcher>
+where
+    F: Fn(FetchedExperiments) -> Result<()> + Send + 'static,
+{
+    let (tx, rx) = channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE_DELAY).map_err(watch_error)?;
+    // Editors often save a file by replacing it, which would end a watch on
+    // the file itself, so we watch the directory it's in instead.
+    let watched_path = match path.parent() {
+        Some(parent) if path.is_file() => parent.to_path_buf(),
+        _ => path.clone(),
+    };
+    watcher
+        .watch(&watched_path, RecursiveMode::NonRecursive)
+        .map_err(watch_error)?;
+    log::info!("watching {} for changed experiments", path.display());
+
+    let client = FileSystemClient::new(&path)?;
+    thread::spawn(move || {
+        for event in rx {
+            match event {
+                DebouncedEvent::Create(_)
+                | DebouncedEvent::Write(_)
+                | DebouncedEvent::Remove(_)
+                | DebouncedEvent::Rename(_, _)
+                | DebouncedEvent::Rescan => {}
+                DebouncedEvent::Error(e, _) => {
+                    log::warn!("Error watching {}: {}", path.display(), e);
+                    continue;
+                }
+                _ => continue,
+            }
+            log::info!("experiments in {} changed", path.display());
+            if let Err(e) = client.fetch_experiments().and_then(&on_change) {
+                log::warn!("Failed to update the changed experiments: {}", e);
+            }
+        }
+        log::info!("stopped watching {}", path.display());
+    });
+    Ok(ExperimentsWatcher { _watcher: watcher })
+}
+
+fn watch_error(e: notify::Error) -> Error {
+    Error::WatchError(e.to_string())
+}
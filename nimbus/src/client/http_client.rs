@@ -13,74 +13,226 @@
 //!
 //! But the simple subset implemented here meets our needs for now.
 
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
-use crate::config::RemoteSettingsConfig;
+use super::signatures::verify_content_signature;
+use super::{FetchedExperiments, RejectedExperiment, RejectionReason};
+use crate::config::{RemoteSettingsConfig, RequestErrorKind, RetryPolicy};
+use crate::dates::now_secs;
 use crate::error::{Error, Result};
-use crate::{Experiment, SettingsClient, SCHEMA_VERSION};
-use std::cell::Cell;
+use crate::{
+    Experiment, RandomizationUnit, RemoteMetadata, SettingsClient, SettingsClientState,
+    SCHEMA_VERSION,
+};
+use ring::rand::{SecureRandom, SystemRandom};
 use url::Url;
-use viaduct::{status_codes, Request, Response};
+use viaduct::{status_codes, Method, Request, Response};
 
 const HEADER_BACKOFF: &str = "Backoff";
 const HEADER_RETRY_AFTER: &str = "Retry-After";
+const HEADER_ETAG: &str = "ETag";
+const HEADER_IF_NONE_MATCH: &str = "If-None-Match";
+const HEADER_TOTAL_RECORDS: &str = "Total-Records";
 
 pub struct Client {
     base_url: Url,
     collection_name: String,
     bucket_name: String,
-    remote_state: Cell<RemoteState>,
+    // The public key the collection's content signature is verified with.
+    trust_root: Option<Vec<u8>>,
+    retry_policy: RetryPolicy,
+    // Behind a `Mutex` rather than a `Cell`, so that the client can be shared
+    // between threads without a lock around it, which would be held while a
+    // request waits to be retried.
+    remote_state: Mutex<RemoteState>,
 }
 
 #[derive(Clone, Copy, Debug)]
 enum RemoteState {
     Ok,
-    Backoff {
-        observed_at: Instant,
-        duration: Duration,
-    },
+    // We back off until a wall-clock time, in seconds since the Unix epoch,
+    // rather than an `Instant`, so that the deadline can be persisted.
+    Backoff { until: u64 },
 }
 
 impl Client {
     #[allow(unused)]
     pub fn new(config: RemoteSettingsConfig) -> Result<Self> {
         let base_url = Url::parse(&config.server_url)?;
+        let trust_root = match config.trust_root {
+            Some(trust_root) => Some(
+                base64::decode(trust_root)
+                    .map_err(|e| Error::SignatureError(format!("invalid trust root: {}", e)))?,
+            ),
+            None => None,
+        };
         Ok(Self {
             base_url,
             bucket_name: config.bucket_name,
             collection_name: config.collection_name,
-            remote_state: Cell::new(RemoteState::Ok),
+            trust_root,
+            retry_policy: config.retry_policy.unwrap_or_default(),
+            remote_state: Mutex::new(RemoteState::Ok),
         })
     }
 
     fn make_request(&self, request: Request) -> Result<Response> {
-        self.ensure_no_backoff()?;
-        let resp = request.send()?;
-        self.handle_backoff_hint(&resp)?;
-        if resp.is_success() || resp.status == status_codes::NOT_MODIFIED {
-            Ok(resp)
-        } else {
-            Err(Error::ResponseError(resp.text().to_string()))
+        let started = Instant::now();
+        let timeout = Duration::from_millis(self.retry_policy.timeout_ms);
+        let mut attempt = 1;
+        loop {
+            self.ensure_no_backoff()?;
+            let (error, retryable) = match request.clone().send() {
+                Ok(resp) => {
+                    self.handle_backoff_hint(&resp)?;
+                    if resp.is_success() || resp.status == status_codes::NOT_MODIFIED {
+                        return Ok(resp);
+                    }
+                    let retryable = self
+                        .retry_policy
+                        .retryable_status_codes
+                        .contains(&resp.status);
+                    (Error::ResponseError(resp.text().to_string()), retryable)
+                }
+                Err(e) => {
+                    let retryable = request_error_kind(&e).map_or(false, |kind| {
+                        self.retry_policy.retryable_errors.contains(&kind)
+                    });
+                    (e.into(), retryable)
+                }
+            };
+            // If the server asked us to back off, we do that rather than retry.
+            if !retryable
+                || attempt >= self.retry_policy.max_attempts
+                || self.backoff_until().is_some()
+            {
+                return Err(error);
+            }
+            let delay = retry_delay(&self.retry_policy, attempt);
+            let retry_at = started.elapsed().checked_add(delay);
+            if retry_at.map_or(true, |retry_at| retry_at >= timeout) {
+                log::warn!("Request failed ({}), and timed out before retrying", error);
+                return Err(error);
+            }
+            log::warn!(
+                "Request failed ({}), retrying in {}ms",
+                error,
+                delay.as_millis()
+            );
+            std::thread::sleep(delay);
+            attempt += 1;
         }
     }
 
     fn ensure_no_backoff(&self) -> Result<()> {
-        if let RemoteState::Backoff {
-            observed_at,
-            duration,
-        } = self.remote_state.get()
-        {
-            let elapsed_time = observed_at.elapsed();
-            if elapsed_time >= duration {
-                self.remote_state.replace(RemoteState::Ok);
+        let mut remote_state = self.remote_state.lock().unwrap();
+        if let RemoteState::Backoff { until } = *remote_state {
+            let now = now_secs();
+            if now >= until {
+                *remote_state = RemoteState::Ok;
             } else {
-                let remaining = duration - elapsed_time;
-                return Err(Error::BackoffError(remaining.as_secs()));
+                return Err(Error::BackoffError(until - now));
             }
         }
         Ok(())
     }
 
+    fn backoff_until(&self) -> Option<u64> {
+        match *self.remote_state.lock().unwrap() {
+            RemoteState::Ok => None,
+            RemoteState::Backoff { until } => Some(until),
+        }
+    }
+
+    // Makes requests with `f`, first backing off if `state` says the server
+    // asked us to, eg in an earlier run of the app or another client using the
+    // same database, and then recording in `state` whether we need to back off.
+    fn with_persisted_backoff<T>(
+        &self,
+        state: &mut SettingsClientState,
+        f: impl FnOnce(&mut SettingsClientState) -> Result<T>,
+    ) -> Result<T> {
+        if let Some(until) = state.backoff_until {
+            if self.backoff_until().map_or(true, |current| until > current) {
+                *self.remote_state.lock().unwrap() = RemoteState::Backoff { until };
+            }
+        }
+        let result = f(state);
+        state.backoff_until = self.backoff_until();
+        result
+    }
+
+    // We use `If-None-Match` rather than `_since`, as the latter only returns
+    // the records which changed, and we always want the full list.
+    fn fetch_records_if_modified(
+        &self,
+        state: &mut SettingsClientState,
+    ) -> Result<Option<FetchedExperiments>> {
+        let mut req = Request::get(self.records_url()?);
+        if let Some(etag) = &state.etag {
+            req = req.header(HEADER_IF_NONE_MATCH, etag.clone())?;
+        }
+        let resp = self.make_request(req)?;
+        if resp.status == status_codes::NOT_MODIFIED {
+            log::info!("Experiments have not been modified since the last fetch");
+            return Ok(None);
+        }
+        let experiments = self.parse_verified_experiments(&resp.text())?;
+        state.etag = resp.headers.get(HEADER_ETAG).map(str::to_string);
+        Ok(Some(experiments))
+    }
+
+    fn collection_url(&self) -> Result<Url> {
+        let path = format!(
+            "buckets/{}/collections/{}",
+            &self.bucket_name, &self.collection_name
+        );
+        Ok(self.base_url.join(&path)?)
+    }
+
+    fn records_url(&self) -> Result<Url> {
+        let path = format!(
+            "buckets/{}/collections/{}/records",
+            &self.bucket_name, &self.collection_name
+        );
+        Ok(self.base_url.join(&path)?)
+    }
+
+    // Returns the body of the collection's metadata response.
+    fn fetch_collection_metadata(&self) -> Result<String> {
+        let req = Request::get(self.collection_url()?);
+        let resp = self.make_request(req)?;
+        Ok(resp.text().to_string())
+    }
+
+    // Returns the number of records in the collection. Kinto gives this in the
+    // `Total-Records` header of a `HEAD` request, so that we don't need to
+    // download the records to count them.
+    fn fetch_record_count(&self) -> Result<u64> {
+        let req = Request::new(Method::Head, self.records_url()?);
+        let resp = self.make_request(req)?;
+        resp.headers
+            .get_as::<u64, _>(HEADER_TOTAL_RECORDS)
+            .and_then(|count| count.ok())
+            .ok_or_else(|| {
+                Error::ResponseError(format!(
+                    "missing or invalid {} header",
+                    HEADER_TOTAL_RECORDS
+                ))
+            })
+    }
+
+    // Parses the experiments in a `/records` response, first checking the
+    // collection's content signature if we have a trust root.
+    fn parse_verified_experiments(&self, payload: &str) -> Result<FetchedExperiments> {
+        if let Some(public_key) = &self.trust_root {
+            let metadata = self.fetch_collection_metadata()?;
+            verify_content_signature(payload, &metadata, public_key)?;
+        }
+        parse_experiments(payload)
+    }
+
     fn handle_backoff_hint(&self, response: &Response) -> Result<()> {
         let extract_backoff_header = |header| -> Result<u64> {
             Ok(response
@@ -96,83 +248,193 @@ impl Client {
         let max_backoff = backoff.max(retry_after);
 
         if max_backoff > 0 {
-            self.remote_state.replace(RemoteState::Backoff {
-                observed_at: Instant::now(),
-                duration: Duration::from_secs(max_backoff),
-            });
+            *self.remote_state.lock().unwrap() = RemoteState::Backoff {
+                until: now_secs() + max_backoff,
+            };
         }
         Ok(())
     }
 }
 
 impl SettingsClient for Client {
-    fn get_experiments_metadata(&self) -> Result<String> {
-        unimplemented!();
+    fn get_experiments_metadata(&self) -> Result<RemoteMetadata> {
+        let metadata: serde_json::Value = serde_json::from_str(&self.fetch_collection_metadata()?)?;
+        let metadata = &metadata["data"];
+        let signature = &metadata["signature"];
+        Ok(RemoteMetadata {
+            source: self.collection_url()?.to_string(),
+            last_modified: metadata["last_modified"].as_u64(),
+            record_count: self.fetch_record_count()?,
+            signature: signature["signature"].as_str().map(str::to_string),
+            signature_x5u: signature["x5u"].as_str().map(str::to_string),
+            signature_verification_enabled: self.trust_root.is_some(),
+        })
     }
 
-    fn fetch_experiments(&self) -> Result<Vec<Experiment>> {
-        let path = format!(
-            "buckets/{}/collections/{}/records",
-            &self.bucket_name, &self.collection_name
-        );
-        let url = self.base_url.join(&path)?;
-        let req = Request::get(url);
+    fn fetch_experiments(&self) -> Result<FetchedExperiments> {
+        let req = Request::get(self.records_url()?);
         let resp = self.make_request(req)?;
-        parse_experiments(&resp.text())
+        self.parse_verified_experiments(&resp.text())
+    }
+
+    fn get_experiments_metadata_with_state(
+        &self,
+        state: &mut SettingsClientState,
+    ) -> Result<RemoteMetadata> {
+        self.with_persisted_backoff(state, |_| self.get_experiments_metadata())
+    }
+
+    fn fetch_experiments_if_modified(
+        &self,
+        state: &mut SettingsClientState,
+    ) -> Result<Option<FetchedExperiments>> {
+        self.with_persisted_backoff(state, |state| self.fetch_records_if_modified(state))
+    }
+}
+
+// The kind of an error a request failed with before we got a response, or
+// `None` if retrying can't help, eg because the URL is invalid.
+fn request_error_kind(error: &viaduct::Error) -> Option<RequestErrorKind> {
+    match error {
+        viaduct::Error::NetworkError(_) => Some(RequestErrorKind::Network),
+        viaduct::Error::BackendError(_) => Some(RequestErrorKind::Backend),
+        _ => None,
     }
 }
 
-pub fn parse_experiments(payload: &str) -> Result<Vec<Experiment>> {
+// The delay before the given retry of a request, which doubles for each
+// retry, plus some jitter.
+fn retry_delay(policy: &RetryPolicy, attempt: u32) -> Duration {
+    let backoff = policy
+        .base_delay_ms
+        .saturating_mul(1 << (attempt - 1).min(16));
+    let jitter = if policy.max_jitter_ms > 0 {
+        let mut bytes = [0u8; 8];
+        // If we can't get a random number, we just don't add any jitter.
+        match SystemRandom::new().fill(&mut bytes) {
+            Ok(()) => u64::from_le_bytes(bytes) % (policy.max_jitter_ms + 1),
+            Err(_) => 0,
+        }
+    } else {
+        0
+    };
+    Duration::from_millis(backoff.saturating_add(jitter))
+}
+
+pub(crate) fn parse_experiments(payload: &str) -> Result<FetchedExperiments> {
     // We first encode the response into a `serde_json::Value`
     // to allow us to deserialize each experiment individually,
     // omitting any malformed experiments
     let value: serde_json::Value = serde_json::from_str(payload)?;
     let data = value.get("data").ok_or(Error::InvalidExperimentFormat)?;
-    let mut res = Vec::new();
+    let mut fetched = FetchedExperiments::default();
     for exp in data.as_array().ok_or(Error::InvalidExperimentFormat)? {
-        // Validate the schema major version matches the supported version
-        let exp_schema_version = match exp.get("schemaVersion") {
-            Some(ver) => {
-                serde_json::from_value::<String>(ver.to_owned()).unwrap_or_else(|_| "".to_string())
-            }
-            None => {
-                log::trace!("Missing schemaVersion: {:#?}", exp);
-                continue;
-            }
-        };
-        let schema_maj_version = exp_schema_version.split('.').next().unwrap_or("");
-        // While "0" is a valid schema version, we have already passed that so reserving zero as
-        // a special value here in order to avoid a panic, and just ignore the experiment.
-        let schema_version: u32 = schema_maj_version.parse().unwrap_or(0);
-        if schema_version != SCHEMA_VERSION {
-            log::info!(
-                    "Schema version mismatch: Expected version {}, discarding experiment with version {}",
-                    SCHEMA_VERSION, schema_version
-                );
-            // Schema version mismatch
-            continue;
+        match parse_experiment(exp) {
+            Ok(exp) => fetched.experiments.push(exp),
+            Err(rejected) => fetched.rejected.push(rejected),
         }
+    }
+    Ok(fetched)
+}
 
-        match serde_json::from_value::<Experiment>(exp.clone()) {
-            Ok(exp) => res.push(exp),
-            Err(e) => {
-                log::trace!("Malformed experiment data: {:#?}", exp);
-                log::warn!(
-                    "Malformed experiment found! Experiment {},  Error: {}",
-                    exp.get("id").unwrap_or(&serde_json::json!("ID_NOT_FOUND")),
-                    e
-                );
-            }
+// Deserializes a single experiment record, checking that its schema major
+// version matches the supported version. If we can't use the record, returns
+// why not.
+pub(crate) fn parse_experiment(exp: &serde_json::Value) -> Result<Experiment, RejectedExperiment> {
+    let exp_schema_version = match exp.get("schemaVersion") {
+        Some(ver) => {
+            serde_json::from_value::<String>(ver.to_owned()).unwrap_or_else(|_| "".to_string())
+        }
+        None => {
+            log::trace!("Missing schemaVersion: {:#?}", exp);
+            return Err(rejected_experiment(
+                exp,
+                RejectionReason::MissingSchemaVersion,
+                None,
+                "missing schemaVersion".to_string(),
+            ));
+        }
+    };
+    let schema_maj_version = exp_schema_version.split('.').next().unwrap_or("");
+    // While "0" is a valid schema version, we have already passed that so reserving zero as
+    // a special value here in order to avoid a panic, and just ignore the experiment.
+    let schema_version: u32 = schema_maj_version.parse().unwrap_or(0);
+    if schema_version != SCHEMA_VERSION {
+        log::info!(
+            "Schema version mismatch: Expected version {}, discarding experiment with version {}",
+            SCHEMA_VERSION,
+            schema_version
+        );
+        return Err(rejected_experiment(
+            exp,
+            RejectionReason::SchemaVersionMismatch,
+            None,
+            format!("unsupported schema version {:?}", exp_schema_version),
+        ));
+    }
+
+    // A randomization unit we don't know about, eg one added after this
+    // version of the SDK, is worth telling apart from a malformed experiment.
+    let unit = exp.pointer("/bucketConfig/randomizationUnit");
+    if let Some(unit) = unit.filter(|unit| unit.is_string()) {
+        if let Err(e) = serde_json::from_value::<RandomizationUnit>(unit.clone()) {
+            log::warn!(
+                "Unknown randomization unit in experiment {}: {}",
+                exp.get("id").unwrap_or(&serde_json::json!("ID_NOT_FOUND")),
+                e
+            );
+            return Err(rejected_experiment(
+                exp,
+                RejectionReason::UnknownRandomizationUnit,
+                Some("bucketConfig.randomizationUnit".to_string()),
+                e.to_string(),
+            ));
         }
     }
-    Ok(res)
+
+    // We track the path to the field that fails to deserialize, so that we
+    // can report it.
+    serde_path_to_error::deserialize::<_, Experiment>(exp.clone()).map_err(|e| {
+        log::trace!("Malformed experiment data: {:#?}", exp);
+        log::warn!(
+            "Malformed experiment found! Experiment {},  Error: {}",
+            exp.get("id").unwrap_or(&serde_json::json!("ID_NOT_FOUND")),
+            e
+        );
+        // The path of the experiment itself is ".".
+        let field_path = Some(e.path().to_string()).filter(|path| path != ".");
+        rejected_experiment(
+            exp,
+            RejectionReason::Malformed,
+            field_path,
+            e.into_inner().to_string(),
+        )
+    })
+}
+
+fn rejected_experiment(
+    exp: &serde_json::Value,
+    reason: RejectionReason,
+    field_path: Option<String>,
+    message: String,
+) -> RejectedExperiment {
+    let get_string = |key| exp.get(key).and_then(|v| v.as_str()).map(str::to_string);
+    RejectedExperiment {
+        id: get_string("id"),
+        slug: get_string("slug"),
+        source: None,
+        reason,
+        field_path,
+        message,
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::signatures::test_utils::TestSigner;
     use super::*;
-    use crate::{Branch, BucketConfig, RandomizationUnit};
-    use mockito::mock;
+    use crate::{Branch, BucketConfig};
+    use mockito::{mock, Matcher};
 
     fn response_body() -> String {
         format!(
@@ -268,13 +530,16 @@ mod tests {
             server_url: mockito::server_url(),
             bucket_name: "main".to_string(),
             collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: None,
+            additional_sources: vec![],
         };
         let http_client = Client::new(config).unwrap();
         let resp = http_client.fetch_experiments().unwrap();
 
         m.expect(1).assert();
-        assert_eq!(resp.len(), 1);
-        let exp = &resp[0];
+        assert_eq!(resp.experiments.len(), 1);
+        let exp = &resp.experiments[0];
         assert_eq!(
             exp.clone(),
             Experiment {
@@ -310,8 +575,75 @@ mod tests {
                     },
                 ],
                 targeting: None,
+                matcher: None,
+                is_rollout: false,
+                sticky_enrollment: false,
             }
-        )
+        );
+        let rejected: Vec<_> = resp
+            .rejected
+            .iter()
+            .map(|r| (r.slug.as_deref(), r.reason))
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![
+                (
+                    Some("mobile-a-a-example"),
+                    RejectionReason::SchemaVersionMismatch
+                ),
+                (
+                    Some("schema-version-missing"),
+                    RejectionReason::MissingSchemaVersion
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_experiments() {
+        let body: serde_json::Value = serde_json::from_str(&response_body()).unwrap();
+        let mut bad_unit = body["data"][0].clone();
+        bad_unit["bucketConfig"]["randomizationUnit"] = "device_id".into();
+        let mut bad_unit_type = bad_unit.clone();
+        bad_unit_type["bucketConfig"]["randomizationUnit"] = 5.into();
+        let mut bad_count = bad_unit.clone();
+        bad_count["bucketConfig"]["randomizationUnit"] = "nimbus_id".into();
+        bad_count["bucketConfig"]["count"] = "lots".into();
+        let mut missing_slug = bad_count.clone();
+        missing_slug["bucketConfig"]["count"] = 5000.into();
+        missing_slug.as_object_mut().unwrap().remove("slug");
+        let payload =
+            serde_json::json!({ "data": [bad_unit, bad_unit_type, bad_count, missing_slug] });
+
+        let fetched = parse_experiments(&payload.to_string()).unwrap();
+        assert!(fetched.experiments.is_empty());
+        let rejected: Vec<_> = fetched
+            .rejected
+            .iter()
+            .map(|r| (r.reason, r.field_path.as_deref()))
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![
+                (
+                    RejectionReason::UnknownRandomizationUnit,
+                    Some("bucketConfig.randomizationUnit")
+                ),
+                (
+                    RejectionReason::Malformed,
+                    Some("bucketConfig.randomizationUnit")
+                ),
+                (RejectionReason::Malformed, Some("bucketConfig.count")),
+                (RejectionReason::Malformed, None),
+            ]
+        );
+        assert_eq!(
+            fetched.rejected[0].slug.as_deref(),
+            Some("mobile-a-a-example")
+        );
+        assert_eq!(fetched.rejected[3].slug, None);
+        assert_eq!(fetched.rejected[3].message, "missing field `slug`");
     }
 
     #[test]
@@ -330,6 +662,9 @@ mod tests {
             server_url: mockito::server_url(),
             bucket_name: "main".to_string(),
             collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: None,
+            additional_sources: vec![],
         };
         let http_client = Client::new(config).unwrap();
         assert!(http_client.fetch_experiments().is_ok());
@@ -353,6 +688,9 @@ mod tests {
             server_url: mockito::server_url(),
             bucket_name: "main".to_string(),
             collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: None,
+            additional_sources: vec![],
         };
         let http_client = Client::new(config).unwrap();
         assert!(http_client.fetch_experiments().is_err());
@@ -376,23 +714,466 @@ mod tests {
             server_url: mockito::server_url(),
             bucket_name: "main".to_string(),
             collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: None,
+            additional_sources: vec![],
         };
         let mut http_client = Client::new(config).unwrap();
         // First, sanity check that manipulating the remote state does something.
-        http_client.remote_state.replace(RemoteState::Backoff {
-            observed_at: Instant::now(),
-            duration: Duration::from_secs(30),
-        });
+        *http_client.remote_state.lock().unwrap() = RemoteState::Backoff {
+            until: now_secs() + 30,
+        };
         assert!(matches!(
             http_client.fetch_experiments(),
             Err(Error::BackoffError(_))
         ));
         // Then do the actual test.
-        http_client.remote_state = Cell::new(RemoteState::Backoff {
-            observed_at: Instant::now() - Duration::from_secs(31),
-            duration: Duration::from_secs(30),
-        });
+        *http_client.remote_state.lock().unwrap() = RemoteState::Backoff {
+            until: now_secs() - 1,
+        };
         assert!(http_client.fetch_experiments().is_ok());
         m.expect(1).assert();
     }
+
+    #[test]
+    fn test_fetch_experiments_if_modified() {
+        viaduct_reqwest::use_reqwest_backend();
+        let m1 = mock(
+            "GET",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .match_header("If-None-Match", Matcher::Missing)
+        .with_body(response_body())
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_header("ETag", "\"1602197324372\"")
+        .create();
+        let m2 = mock(
+            "GET",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .match_header("If-None-Match", "\"1602197324372\"")
+        .with_status(304)
+        .create();
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: None,
+            additional_sources: vec![],
+        };
+        let http_client = Client::new(config).unwrap();
+        let mut state = SettingsClientState::default();
+        let experiments = http_client
+            .fetch_experiments_if_modified(&mut state)
+            .unwrap();
+        assert_eq!(experiments.unwrap().experiments.len(), 1);
+        assert_eq!(state.etag.as_deref(), Some("\"1602197324372\""));
+        // The experiments haven't changed, so we don't get them again.
+        let experiments = http_client
+            .fetch_experiments_if_modified(&mut state)
+            .unwrap();
+        assert!(experiments.is_none());
+        assert_eq!(state.etag.as_deref(), Some("\"1602197324372\""));
+        m1.expect(1).assert();
+        m2.expect(1).assert();
+    }
+
+    #[test]
+    fn test_fetch_signed_experiments() {
+        viaduct_reqwest::use_reqwest_backend();
+        let signer = TestSigner::new();
+        // We only ask for the number of records, rather than fetching them.
+        let m1 = mock(
+            "HEAD",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_status(200)
+        .with_header("Total-Records", "3")
+        .create();
+        let m2 = mock("GET", "/buckets/main/collections/messaging-experiments")
+            .with_body(signer.metadata_payload(&response_body(), 1_602_197_324_372))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .create();
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: Some(base64::encode(signer.public_key())),
+            retry_policy: None,
+            additional_sources: vec![],
+        };
+        let http_client = Client::new(config).unwrap();
+        let resp = http_client.fetch_experiments().unwrap();
+        assert_eq!(resp.experiments.len(), 1);
+        m1.expect(1).assert();
+        m2.expect(1).assert();
+    }
+
+    #[test]
+    fn test_fetch_experiments_bad_signature() {
+        viaduct_reqwest::use_reqwest_backend();
+        let signer = TestSigner::new();
+        let m1 = mock(
+            "GET",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_body(response_body())
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .create();
+        // The signature is for different records than the ones we receive.
+        let m2 = mock("GET", "/buckets/main/collections/messaging-experiments")
+            .with_body(signer.metadata_payload(r#"{"data": []}"#, 1_602_197_324_372))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .create();
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: Some(base64::encode(signer.public_key())),
+            retry_policy: None,
+            additional_sources: vec![],
+        };
+        let http_client = Client::new(config).unwrap();
+        assert!(matches!(
+            http_client.fetch_experiments(),
+            Err(Error::SignatureError(_))
+        ));
+        m1.expect(1).assert();
+        m2.expect(1).assert();
+    }
+
+    #[test]
+    fn test_invalid_trust_root() {
+        let config = RemoteSettingsConfig {
+            server_url: "https://example.com".to_string(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: Some("not base64!".to_string()),
+            retry_policy: None,
+            additional_sources: vec![],
+        };
+        assert!(matches!(Client::new(config), Err(Error::SignatureError(_))));
+    }
+
+    #[test]
+    fn test_get_experiments_metadata() {
+        viaduct_reqwest::use_reqwest_backend();
+        let signer = TestSigner::new();
+        // We only ask for the number of records, rather than fetching them.
+        let m1 = mock(
+            "HEAD",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_status(200)
+        .with_header("Total-Records", "3")
+        .create();
+        let m2 = mock("GET", "/buckets/main/collections/messaging-experiments")
+            .with_body(signer.metadata_payload(&response_body(), 1_602_197_324_372))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .create();
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: None,
+            additional_sources: vec![],
+        };
+        let http_client = Client::new(config).unwrap();
+        let metadata = http_client.get_experiments_metadata().unwrap();
+        assert_eq!(
+            metadata.source,
+            format!(
+                "{}/buckets/main/collections/messaging-experiments",
+                mockito::server_url()
+            )
+        );
+        assert_eq!(metadata.last_modified, Some(1_602_197_324_372));
+        assert_eq!(metadata.record_count, 3);
+        assert!(metadata.signature.is_some());
+        assert_eq!(metadata.signature_x5u, None);
+        assert!(!metadata.signature_verification_enabled);
+        m1.expect(1).assert();
+        m2.expect(1).assert();
+    }
+
+    #[test]
+    fn test_backoff_persisted() {
+        viaduct_reqwest::use_reqwest_backend();
+        let m = mock(
+            "GET",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_body("Boom!")
+        .with_status(500)
+        .with_header("Retry-After", "60")
+        .create();
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: None,
+            additional_sources: vec![],
+        };
+        let http_client = Client::new(config.clone()).unwrap();
+        let mut state = SettingsClientState::default();
+        assert!(http_client
+            .fetch_experiments_if_modified(&mut state)
+            .is_err());
+        let backoff_until = state.backoff_until.unwrap();
+        assert!(backoff_until >= now_secs() + 59 && backoff_until <= now_secs() + 60);
+        // A new client, eg after the app restarts, keeps backing off.
+        let http_client = Client::new(config).unwrap();
+        assert!(matches!(
+            http_client.fetch_experiments_if_modified(&mut state),
+            Err(Error::BackoffError(_))
+        ));
+        assert_eq!(state.backoff_until, Some(backoff_until));
+        m.expect(1).assert();
+    }
+
+    #[test]
+    fn test_backoff_persisted_expired() {
+        viaduct_reqwest::use_reqwest_backend();
+        let m = mock(
+            "GET",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_body(response_body())
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .create();
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: None,
+            additional_sources: vec![],
+        };
+        let http_client = Client::new(config).unwrap();
+        let mut state = SettingsClientState {
+            backoff_until: Some(now_secs() - 1),
+            ..Default::default()
+        };
+        assert!(http_client
+            .fetch_experiments_if_modified(&mut state)
+            .is_ok());
+        assert_eq!(state.backoff_until, None);
+        m.expect(1).assert();
+    }
+
+    #[test]
+    fn test_get_experiments_metadata_backoff_persisted() {
+        viaduct_reqwest::use_reqwest_backend();
+        let m = mock("GET", "/buckets/main/collections/messaging-experiments")
+            .with_body("Boom!")
+            .with_status(500)
+            .with_header("Backoff", "60")
+            .create();
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: None,
+            additional_sources: vec![],
+        };
+        let http_client = Client::new(config.clone()).unwrap();
+        let mut state = SettingsClientState::default();
+        assert!(http_client
+            .get_experiments_metadata_with_state(&mut state)
+            .is_err());
+        let backoff_until = state.backoff_until.unwrap();
+        assert!(backoff_until >= now_secs() + 59 && backoff_until <= now_secs() + 60);
+        // A new client backs off from every request, not only fetching the
+        // experiments.
+        let http_client = Client::new(config).unwrap();
+        assert!(matches!(
+            http_client.get_experiments_metadata_with_state(&mut state),
+            Err(Error::BackoffError(_))
+        ));
+        assert!(matches!(
+            http_client.fetch_experiments(),
+            Err(Error::BackoffError(_))
+        ));
+        assert_eq!(state.backoff_until, Some(backoff_until));
+        m.expect(1).assert();
+    }
+
+    fn retry_policy(max_attempts: u32) -> Option<RetryPolicy> {
+        Some(RetryPolicy {
+            max_attempts,
+            base_delay_ms: 0,
+            max_jitter_ms: 0,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_retry_server_error() {
+        viaduct_reqwest::use_reqwest_backend();
+        // The server fails the first time, then succeeds.
+        let m1 = mock(
+            "GET",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_body("Boom!")
+        .with_status(503)
+        .expect(1)
+        .create();
+        let m2 = mock(
+            "GET",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_body(response_body())
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .expect(1)
+        .create();
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: retry_policy(3),
+            additional_sources: vec![],
+        };
+        let http_client = Client::new(config).unwrap();
+        assert_eq!(
+            http_client.fetch_experiments().unwrap().experiments.len(),
+            1
+        );
+        m1.assert();
+        m2.assert();
+    }
+
+    #[test]
+    fn test_retry_max_attempts() {
+        viaduct_reqwest::use_reqwest_backend();
+        let m = mock(
+            "GET",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_body("Boom!")
+        .with_status(500)
+        .create();
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: retry_policy(2),
+            additional_sources: vec![],
+        };
+        let http_client = Client::new(config).unwrap();
+        assert!(matches!(
+            http_client.fetch_experiments(),
+            Err(Error::ResponseError(_))
+        ));
+        m.expect(2).assert();
+    }
+
+    #[test]
+    fn test_retry_timeout() {
+        viaduct_reqwest::use_reqwest_backend();
+        let m = mock(
+            "GET",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_body("Boom!")
+        .with_status(500)
+        .create();
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: Some(RetryPolicy {
+                base_delay_ms: 60_000,
+                timeout_ms: 1000,
+                ..retry_policy(3).unwrap()
+            }),
+            additional_sources: vec![],
+        };
+        let http_client = Client::new(config).unwrap();
+        // We don't wait a minute to retry when the request must be done in a second.
+        assert!(matches!(
+            http_client.fetch_experiments(),
+            Err(Error::ResponseError(_))
+        ));
+        m.expect(1).assert();
+    }
+
+    #[test]
+    fn test_retryable_errors() {
+        let policy = RetryPolicy::default();
+        let network_error = viaduct::Error::NetworkError("offline".to_string());
+        let backend_error = viaduct::Error::BackendError("boom".to_string());
+        assert_eq!(
+            request_error_kind(&network_error),
+            Some(RequestErrorKind::Network)
+        );
+        assert_eq!(
+            request_error_kind(&backend_error),
+            Some(RequestErrorKind::Backend)
+        );
+        assert_eq!(
+            request_error_kind(&viaduct::Error::BackendNotInitialized),
+            None
+        );
+        assert_eq!(policy.retryable_errors, vec![RequestErrorKind::Network]);
+    }
+
+    #[test]
+    fn test_no_retry_client_error() {
+        viaduct_reqwest::use_reqwest_backend();
+        let m = mock(
+            "GET",
+            "/buckets/main/collections/messaging-experiments/records",
+        )
+        .with_body("Not found")
+        .with_status(404)
+        .create();
+        let config = RemoteSettingsConfig {
+            server_url: mockito::server_url(),
+            bucket_name: "main".to_string(),
+            collection_name: "messaging-experiments".to_string(),
+            trust_root: None,
+            retry_policy: retry_policy(3),
+            additional_sources: vec![],
+        };
+        let http_client = Client::new(config).unwrap();
+        assert!(matches!(
+            http_client.fetch_experiments(),
+            Err(Error::ResponseError(_))
+        ));
+        m.expect(1).assert();
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy {
+            base_delay_ms: 100,
+            max_jitter_ms: 50,
+            ..Default::default()
+        };
+        for (attempt, backoff) in &[(1, 100), (2, 200), (3, 400)] {
+            let delay = retry_delay(&policy, *attempt).as_millis() as u64;
+            assert!(delay >= *backoff && delay <= backoff + 50);
+        }
+        let policy = RetryPolicy {
+            base_delay_ms: 100,
+            max_jitter_ms: 0,
+            ..Default::default()
+        };
+        assert_eq!(retry_delay(&policy, 4), Duration::from_millis(800));
+    }
 }
@@ -3,47 +3,196 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 mod fs_client;
+#[cfg(feature = "fs-watch")]
+mod fs_watcher;
 mod http_client;
+mod multi_client;
 mod null_client;
+mod signatures;
 use crate::error::{Error, Result};
 use crate::Experiment;
 use crate::RemoteSettingsConfig;
 use fs_client::FileSystemClient;
 use http_client::Client;
+use multi_client::MultiClient;
 use null_client::NullClient;
+use serde_derive::*;
+use std::collections::HashMap;
+#[cfg(feature = "fs-watch")]
+use std::path::Path;
 use url::Url;
 
-pub use http_client::parse_experiments;
+#[cfg(feature = "fs-watch")]
+pub(crate) use fs_watcher::watch_experiments;
+#[cfg(feature = "fs-watch")]
+pub use fs_watcher::ExperimentsWatcher;
+pub(crate) use http_client::parse_experiments;
 
 pub(crate) fn create_client(
     config: Option<RemoteSettingsConfig>,
-) -> Result<Box<dyn SettingsClient + Send>> {
+) -> Result<Box<dyn SettingsClient + Send + Sync>> {
     Ok(match config {
-        Some(config) => {
-            // XXX - double-parsing the URL here if it's not a file:// URL - ideally
-            // config would already be holding a Url and we wouldn't parse here at all.
-            let url = Url::parse(&config.server_url)?;
-            if url.scheme() == "file" {
-                // Everything in `config` other than the url/path is ignored for the
-                // file-system - we could insist on a sub-directory, but that doesn't
-                // seem valuable for the use-cases we care about here.
-                let path = match url.to_file_path() {
-                    Ok(path) => path,
-                    _ => return Err(Error::InvalidPath(config.server_url)),
-                };
-                Box::new(FileSystemClient::new(path)?)
-            } else {
-                Box::new(Client::new(config)?)
-            }
-        }
+        Some(config) if config.additional_sources.is_empty() => create_source_client(config)?,
+        Some(config) => Box::new(MultiClient::new(config)?),
         // If no server is provided, then we still want Nimbus to work, but serving
         // an empty list of experiments.
         None => Box::new(NullClient::new()),
     })
 }
 
+/// The collection that experiments are previewed in before they are launched.
+pub(crate) const PREVIEW_COLLECTION_NAME: &str = "nimbus-preview";
+
+// Creates the client used in preview mode, which fetches from the preview
+// collection on the same server instead of the configured collection(s).
+// The file-system has no collections, so for a `file://` URL this reads the
+// same directory as the main client.
+pub(crate) fn create_preview_client(
+    config: Option<RemoteSettingsConfig>,
+) -> Result<Box<dyn SettingsClient + Send + Sync>> {
+    create_client(config.map(|config| RemoteSettingsConfig {
+        collection_name: PREVIEW_COLLECTION_NAME.to_string(),
+        additional_sources: Vec::new(),
+        ..config
+    }))
+}
+
+// Creates the client for the source named by `config`, ignoring any additional sources.
+fn create_source_client(
+    config: RemoteSettingsConfig,
+) -> Result<Box<dyn SettingsClient + Send + Sync>> {
+    // XXX - double-parsing the URL here if it's not a file:// URL - ideally
+    // config would already be holding a Url and we wouldn't parse here at all.
+    let url = Url::parse(&config.server_url)?;
+    Ok(if url.scheme() == "file" {
+        // Everything in `config` other than the url/path is ignored for the
+        // file-system - we could insist on a sub-directory, but that doesn't
+        // seem valuable for the use-cases we care about here.
+        let path = match url.to_file_path() {
+            Ok(path) => path,
+            _ => return Err(Error::InvalidPath(config.server_url)),
+        };
+        Box::new(FileSystemClient::new(path)?)
+    } else {
+        Box::new(Client::new(config)?)
+    })
+}
+
+// The state a settings client keeps between fetches, which we persist so that
+// it survives the app restarting.
+// ⚠️ Warning : Altering this type might require a DB migration. ⚠️
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub(crate) struct SettingsClientState {
+    // The `ETag` of the last experiments we fetched from the server.
+    pub etag: Option<String>,
+    // When the server asked us to back off until, in seconds since the Unix
+    // epoch, so that we keep backing off across app launches.
+    pub backoff_until: Option<u64>,
+    // The state of each source when fetching from several, keyed by the
+    // source's URL, bucket and collection.
+    pub sources: HashMap<String, SettingsClientState>,
+}
+
+impl SettingsClientState {
+    // Forgets the `ETag`s of the last fetch, including each source's, so that
+    // the next fetch isn't skipped as unmodified.
+    pub fn clear_etags(&mut self) {
+        self.etag = None;
+        for source in self.sources.values_mut() {
+            source.clear_etags();
+        }
+    }
+}
+
+/// Information about where the experiments are fetched from, eg for an app to
+/// show when they were last updated in its debug screens.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RemoteMetadata {
+    /// A description of the source of the experiments, eg the URL of the collection.
+    pub source: String,
+    /// When the experiments were last modified, in milliseconds since the Unix epoch.
+    pub last_modified: Option<u64>,
+    /// The number of records in the source, including any we can't parse.
+    pub record_count: u64,
+    /// The content signature of the experiments, if they are signed.
+    pub signature: Option<String>,
+    /// The URL of the certificate chain for the content signature.
+    pub signature_x5u: Option<String>,
+    /// Whether fetched experiments are checked against the content signature,
+    /// ie whether a trust root is configured. Fetching fails if the check does.
+    pub signature_verification_enabled: bool,
+}
+
+/// An experiment record that we couldn't use, and why.
+// ⚠️ Warning : Altering this type might require a DB migration. ⚠️
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RejectedExperiment {
+    /// The record's `id`, if it has one.
+    pub id: Option<String>,
+    /// The experiment's `slug`, if it has one.
+    pub slug: Option<String>,
+    /// The file the record was read from, for experiments read from the file-system.
+    pub source: Option<String>,
+    pub reason: RejectionReason,
+    /// For malformed experiments, the field that's wrong, eg `bucketConfig.count`,
+    /// or `None` if it's the record itself, eg when a required field is missing.
+    pub field_path: Option<String>,
+    /// A description of the problem, for people rather than machines.
+    pub message: String,
+}
+
+// ⚠️ Warning : Altering this type might require a DB migration. ⚠️
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionReason {
+    /// The record has no `schemaVersion`.
+    MissingSchemaVersion,
+    /// The record's `schemaVersion` isn't one this version of the SDK supports.
+    SchemaVersionMismatch,
+    /// The bucket config has a randomization unit we don't know about.
+    UnknownRandomizationUnit,
+    /// A field is missing, or has the wrong type or value.
+    Malformed,
+    /// The record couldn't be read at all, eg a file that isn't JSON.
+    Unreadable,
+}
+
+// The experiments we fetched from a source, and the records we rejected.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct FetchedExperiments {
+    pub experiments: Vec<Experiment>,
+    pub rejected: Vec<RejectedExperiment>,
+}
+
 // The trait used to fetch experiments.
 pub(crate) trait SettingsClient {
-    fn get_experiments_metadata(&self) -> Result<String>;
-    fn fetch_experiments(&self) -> Result<Vec<Experiment>>;
+    fn get_experiments_metadata(&self) -> Result<RemoteMetadata>;
+    fn fetch_experiments(&self) -> Result<FetchedExperiments>;
+
+    // Like `get_experiments_metadata()`, but backs off if `state` says the
+    // server asked us to, and updates `state` if it asks us to now.
+    fn get_experiments_metadata_with_state(
+        &self,
+        _state: &mut SettingsClientState,
+    ) -> Result<RemoteMetadata> {
+        self.get_experiments_metadata()
+    }
+
+    // Like `fetch_experiments()`, but returns `None` if the experiments haven't
+    // changed since the fetch that `state` was left by. `state` is updated,
+    // ready to be persisted for the next fetch.
+    // Clients that can't tell whether the experiments changed always fetch them.
+    fn fetch_experiments_if_modified(
+        &self,
+        _state: &mut SettingsClientState,
+    ) -> Result<Option<FetchedExperiments>> {
+        self.fetch_experiments().map(Some)
+    }
+
+    // The path the experiments are read from, for clients that read them from
+    // the file-system, so that it can be watched for changes.
+    #[cfg(feature = "fs-watch")]
+    fn local_path(&self) -> Option<&Path> {
+        None
+    }
 }
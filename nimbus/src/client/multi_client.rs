@@ -0,0 +1,451 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A SettingsClient that fetches experiments from several sources, eg a main
+//! collection and a preview collection, and merges them into one list.
+//!
+//! If more than one source has an experiment with the same slug, the one from
+//! the earliest source is used, so the sources are in order of precedence.
+
+use super::FetchedExperiments;
+use crate::error::Result;
+use crate::{RemoteMetadata, RemoteSettingsConfig, SettingsClient};
+use crate::{RemoteSettingsSource, SettingsClientState};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+pub struct MultiClient {
+    // Each source's client, and the key its state is persisted under.
+    sources: Vec<(String, Box<dyn SettingsClient + Send + Sync>)>,
+    // The last experiments we fetched from each source, and their `ETag`, so
+    // that we don't download the sources which haven't changed again when
+    // another has.
+    cache: Mutex<HashMap<String, (String, FetchedExperiments)>>,
+}
+
+impl MultiClient {
+    pub fn new(config: RemoteSettingsConfig) -> Result<Self> {
+        let main_source = RemoteSettingsSource {
+            server_url: config.server_url.clone(),
+            bucket_name: config.bucket_name.clone(),
+            collection_name: config.collection_name.clone(),
+        };
+        let mut sources = Vec::new();
+        for source in std::iter::once(main_source).chain(config.additional_sources.clone()) {
+            let key = format!(
+                "{} {}/{}",
+                source.server_url, source.bucket_name, source.collection_name
+            );
+            // The sources share the rest of the config, eg the trust root.
+            let client = super::create_source_client(RemoteSettingsConfig {
+                server_url: source.server_url,
+                bucket_name: source.bucket_name,
+                collection_name: source.collection_name,
+                additional_sources: Vec::new(),
+                ..config.clone()
+            })?;
+            sources.push((key, client));
+        }
+        Ok(Self::from_sources(sources))
+    }
+
+    fn from_sources(sources: Vec<(String, Box<dyn SettingsClient + Send + Sync>)>) -> Self {
+        Self {
+            sources,
+            cache: Default::default(),
+        }
+    }
+
+    // Fetches the experiments of one source if they've changed, remembering
+    // them if the source gave us an `ETag` to tell when they next change.
+    fn fetch_source_if_modified(
+        &self,
+        key: &str,
+        client: &(dyn SettingsClient + Send + Sync),
+        source_state: &mut SettingsClientState,
+    ) -> Result<Option<FetchedExperiments>> {
+        let fetched = client.fetch_experiments_if_modified(source_state)?;
+        if let (Some(experiments), Some(etag)) = (&fetched, &source_state.etag) {
+            self.cache
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), (etag.clone(), experiments.clone()));
+        }
+        Ok(fetched)
+    }
+
+    // The experiments we last fetched from a source, if they're the ones the
+    // source's `ETag` is for.
+    fn cached_experiments(
+        &self,
+        key: &str,
+        source_state: &SettingsClientState,
+    ) -> Option<FetchedExperiments> {
+        let cache = self.cache.lock().unwrap();
+        match (cache.get(key), &source_state.etag) {
+            (Some((cached_etag, experiments)), Some(etag)) if cached_etag == etag => {
+                Some(experiments.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn fetch_all_if_modified(
+        &self,
+        state: &mut SettingsClientState,
+    ) -> Result<Option<FetchedExperiments>> {
+        let mut fetched = Vec::with_capacity(self.sources.len());
+        for (key, client) in &self.sources {
+            let source_state = state.sources.entry(key.clone()).or_default();
+            fetched.push(self.fetch_source_if_modified(key, client.as_ref(), source_state)?);
+        }
+        if fetched.iter().all(Option::is_none) {
+            return Ok(None);
+        }
+        // We need the experiments from every source to merge them, including
+        // the ones which haven't changed, which we usually have from the last
+        // fetch.
+        let mut all_experiments = Vec::with_capacity(self.sources.len());
+        for ((key, client), experiments) in self.sources.iter().zip(fetched) {
+            let source_state = state.sources.entry(key.clone()).or_default();
+            let experiments = match experiments {
+                Some(experiments) => experiments,
+                None => match self.cached_experiments(key, source_state) {
+                    Some(experiments) => experiments,
+                    None => {
+                        // Without its `ETag`, the source downloads its experiments
+                        // again, and keeps its state about backing off up to date.
+                        source_state.etag = None;
+                        match self.fetch_source_if_modified(key, client.as_ref(), source_state)? {
+                            Some(experiments) => experiments,
+                            None => client.fetch_experiments()?,
+                        }
+                    }
+                },
+            };
+            all_experiments.push(experiments);
+        }
+        Ok(Some(merge_experiments(all_experiments)))
+    }
+
+    // The metadata of the sources combined, getting each source's with
+    // `get_source_metadata`. As there's more than one content signature, we
+    // don't include them.
+    fn combine_metadata(
+        &self,
+        mut get_source_metadata: impl FnMut(
+            &str,
+            &(dyn SettingsClient + Send + Sync),
+        ) -> Result<RemoteMetadata>,
+    ) -> Result<RemoteMetadata> {
+        let mut metadata = RemoteMetadata {
+            signature_verification_enabled: true,
+            ..Default::default()
+        };
+        let mut descriptions = Vec::with_capacity(self.sources.len());
+        for (key, client) in &self.sources {
+            let source_metadata = get_source_metadata(key, client.as_ref())?;
+            descriptions.push(source_metadata.source);
+            metadata.last_modified = metadata.last_modified.max(source_metadata.last_modified);
+            metadata.record_count += source_metadata.record_count;
+            metadata.signature_verification_enabled &=
+                source_metadata.signature_verification_enabled;
+        }
+        metadata.source = descriptions.join(", ");
+        Ok(metadata)
+    }
+}
+
+impl SettingsClient for MultiClient {
+    fn get_experiments_metadata(&self) -> Result<RemoteMetadata> {
+        self.combine_metadata(|_, client| client.get_experiments_metadata())
+    }
+
+    fn get_experiments_metadata_with_state(
+        &self,
+        state: &mut SettingsClientState,
+    ) -> Result<RemoteMetadata> {
+        self.combine_metadata(|key, client| {
+            let source_state = state.sources.entry(key.to_string()).or_default();
+            client.get_experiments_metadata_with_state(source_state)
+        })
+    }
+
+    fn fetch_experiments(&self) -> Result<FetchedExperiments> {
+        let mut all_experiments = Vec::with_capacity(self.sources.len());
+        for (_, client) in &self.sources {
+            all_experiments.push(client.fetch_experiments()?);
+        }
+        Ok(merge_experiments(all_experiments))
+    }
+
+    // If any source fails, so does the whole fetch. In that case we only keep
+    // the state about backing off, as the new `ETag`s are for experiments which
+    // won't be applied.
+    fn fetch_experiments_if_modified(
+        &self,
+        state: &mut SettingsClientState,
+    ) -> Result<Option<FetchedExperiments>> {
+        state
+            .sources
+            .retain(|key, _| self.sources.iter().any(|(k, _)| k == key));
+        let mut new_state = state.clone();
+        let result = self.fetch_all_if_modified(&mut new_state);
+        if result.is_ok() {
+            *state = new_state;
+        } else {
+            for (key, source_state) in new_state.sources {
+                state.sources.entry(key).or_default().backoff_until = source_state.backoff_until;
+            }
+        }
+        result
+    }
+}
+
+// The rejected records of all the sources are kept.
+fn merge_experiments(all_experiments: Vec<FetchedExperiments>) -> FetchedExperiments {
+    let mut slugs = HashSet::new();
+    let mut merged = FetchedExperiments::default();
+    for fetched in all_experiments {
+        merged.rejected.extend(fetched.rejected);
+        for experiment in fetched.experiments {
+            if slugs.insert(experiment.slug.clone()) {
+                merged.experiments.push(experiment);
+            } else {
+                log::info!(
+                    "Ignoring experiment {} from a lower precedence source",
+                    experiment.slug
+                );
+            }
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::Experiment;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // A source with a fixed list of experiments, which the test can mark as
+    // modified or failing.
+    #[derive(Default)]
+    struct TestClient {
+        experiments: Vec<Experiment>,
+        modified: Arc<AtomicBool>,
+        failing: Arc<AtomicBool>,
+        fetches: Arc<AtomicUsize>,
+    }
+
+    impl SettingsClient for TestClient {
+        fn get_experiments_metadata(&self) -> Result<RemoteMetadata> {
+            Ok(Default::default())
+        }
+
+        fn fetch_experiments(&self) -> Result<FetchedExperiments> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(Error::ResponseError("Boom!".to_string()));
+            }
+            Ok(FetchedExperiments {
+                experiments: self.experiments.clone(),
+                ..Default::default()
+            })
+        }
+
+        fn fetch_experiments_if_modified(
+            &self,
+            state: &mut SettingsClientState,
+        ) -> Result<Option<FetchedExperiments>> {
+            if !self.modified.load(Ordering::SeqCst) && state.etag.is_some() {
+                return Ok(None);
+            }
+            let experiments = self.fetch_experiments()?;
+            state.etag = Some("etag".to_string());
+            Ok(Some(experiments))
+        }
+    }
+
+    fn experiment(slug: &str, application: &str) -> Experiment {
+        Experiment {
+            slug: slug.to_string(),
+            application: application.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // Returns a client with a main and a preview source, and the preview
+    // source's `modified` and `failing` flags.
+    fn multi_client() -> (MultiClient, Arc<AtomicBool>, Arc<AtomicBool>) {
+        let main = TestClient {
+            experiments: vec![experiment("a", "main"), experiment("b", "main")],
+            ..Default::default()
+        };
+        let preview = TestClient {
+            experiments: vec![experiment("b", "preview"), experiment("c", "preview")],
+            ..Default::default()
+        };
+        let modified = preview.modified.clone();
+        let failing = preview.failing.clone();
+        let client = MultiClient::from_sources(vec![
+            ("main".to_string(), Box::new(main)),
+            ("preview".to_string(), Box::new(preview)),
+        ]);
+        (client, modified, failing)
+    }
+
+    #[test]
+    fn test_merge_precedence() -> Result<()> {
+        let (client, _, _) = multi_client();
+        let experiments = client.fetch_experiments()?.experiments;
+        let experiments: Vec<_> = experiments
+            .iter()
+            .map(|e| (e.slug.as_str(), e.application.as_str()))
+            .collect();
+        assert_eq!(
+            experiments,
+            vec![("a", "main"), ("b", "main"), ("c", "preview")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_if_modified() -> Result<()> {
+        let (client, modified, _) = multi_client();
+        let mut state = SettingsClientState::default();
+        assert_eq!(
+            client
+                .fetch_experiments_if_modified(&mut state)?
+                .unwrap()
+                .experiments
+                .len(),
+            3
+        );
+        assert_eq!(state.sources.len(), 2);
+
+        // Nothing has changed.
+        assert!(client.fetch_experiments_if_modified(&mut state)?.is_none());
+
+        // If only one source has changed, we still get the experiments from both.
+        modified.store(true, Ordering::SeqCst);
+        assert_eq!(
+            client
+                .fetch_experiments_if_modified(&mut state)?
+                .unwrap()
+                .experiments
+                .len(),
+            3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_if_modified_reuses_unchanged_sources() -> Result<()> {
+        let main = TestClient {
+            experiments: vec![experiment("a", "main")],
+            ..Default::default()
+        };
+        let preview = TestClient {
+            experiments: vec![experiment("c", "preview")],
+            ..Default::default()
+        };
+        let main_fetches = main.fetches.clone();
+        let modified = preview.modified.clone();
+        let client = MultiClient::from_sources(vec![
+            ("main".to_string(), Box::new(main)),
+            ("preview".to_string(), Box::new(preview)),
+        ]);
+        let mut state = SettingsClientState::default();
+        client.fetch_experiments_if_modified(&mut state)?;
+        assert_eq!(main_fetches.load(Ordering::SeqCst), 1);
+
+        // The main source hasn't changed, so we use the experiments we
+        // already have from it rather than downloading them again.
+        modified.store(true, Ordering::SeqCst);
+        let experiments = client
+            .fetch_experiments_if_modified(&mut state)?
+            .unwrap()
+            .experiments;
+        assert_eq!(experiments.len(), 2);
+        assert_eq!(experiments[0].slug, "a");
+        assert_eq!(main_fetches.load(Ordering::SeqCst), 1);
+
+        // Without them, eg after the app restarts, we download them again.
+        let client = MultiClient::from_sources(vec![
+            (
+                "main".to_string(),
+                Box::new(TestClient {
+                    experiments: vec![experiment("a", "main")],
+                    fetches: main_fetches.clone(),
+                    ..Default::default()
+                }),
+            ),
+            (
+                "preview".to_string(),
+                Box::new(TestClient {
+                    experiments: vec![experiment("c", "preview")],
+                    modified,
+                    ..Default::default()
+                }),
+            ),
+        ]);
+        assert_eq!(
+            client
+                .fetch_experiments_if_modified(&mut state)?
+                .unwrap()
+                .experiments
+                .len(),
+            2
+        );
+        assert_eq!(main_fetches.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_if_modified_after_clearing_etags() -> Result<()> {
+        let (client, _, _) = multi_client();
+        let mut state = SettingsClientState::default();
+        client.fetch_experiments_if_modified(&mut state)?;
+        assert!(client.fetch_experiments_if_modified(&mut state)?.is_none());
+
+        // Once the `ETag`s are cleared, we fetch every source again, even
+        // though none of them have changed.
+        state.clear_etags();
+        assert!(state.sources.values().all(|source| source.etag.is_none()));
+        assert_eq!(
+            client
+                .fetch_experiments_if_modified(&mut state)?
+                .unwrap()
+                .experiments
+                .len(),
+            3
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_if_modified_failure() -> Result<()> {
+        let (client, _, failing) = multi_client();
+        let mut state = SettingsClientState::default();
+        failing.store(true, Ordering::SeqCst);
+        assert!(client.fetch_experiments_if_modified(&mut state).is_err());
+        // The main source's `ETag` isn't kept, so we fetch it again next time
+        // rather than skipping it as unmodified.
+        assert_eq!(state.sources["main"].etag, None);
+
+        failing.store(false, Ordering::SeqCst);
+        assert_eq!(
+            client
+                .fetch_experiments_if_modified(&mut state)?
+                .unwrap()
+                .experiments
+                .len(),
+            3
+        );
+        Ok(())
+    }
+}
@@ -2,8 +2,9 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use super::FetchedExperiments;
 use crate::error::Result;
-use crate::{Experiment, SettingsClient};
+use crate::{RemoteMetadata, SettingsClient};
 
 /// This is a client for use when no server is provided.
 /// Its primary use is for non-Mozilla forks of apps that are not using their
@@ -17,10 +18,13 @@ impl NullClient {
 }
 
 impl SettingsClient for NullClient {
-    fn get_experiments_metadata(&self) -> Result<String> {
-        unimplemented!();
+    fn get_experiments_metadata(&self) -> Result<RemoteMetadata> {
+        Ok(RemoteMetadata {
+            source: "none".to_string(),
+            ..Default::default()
+        })
     }
-    fn fetch_experiments(&self) -> Result<Vec<Experiment>> {
+    fn fetch_experiments(&self) -> Result<FetchedExperiments> {
         Ok(Default::default())
     }
 }
@@ -36,11 +40,15 @@ fn test_null_client() -> Result<()> {
     let tmp_dir = TempDir::new("test_null_client-test_null")?;
 
     let aru = Default::default();
-    let client = NimbusClient::new(Default::default(), tmp_dir.path(), None, aru)?;
+    let client = NimbusClient::new(Default::default(), tmp_dir.path(), None, aru, None)?;
     client.fetch_experiments()?;
     client.apply_pending_experiments()?;
 
     let experiments = client.get_all_experiments()?;
     assert_eq!(experiments.len(), 0);
+
+    let metadata = client.get_remote_metadata()?;
+    assert_eq!(metadata.record_count, 0);
+    assert_eq!(metadata.last_modified, None);
     Ok(())
 }
@@ -0,0 +1,238 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Verification of the content signatures of Remote Settings collections.
+//!
+//! The server signs each collection with a P-384 ECDSA key, over the canonical
+//! JSON serialization of its records and its timestamp, and returns the
+//! signature in the collection's metadata. We check that signature against the
+//! trust root the app configured, which is the signer's public key. Unlike
+//! Firefox, we don't fetch and validate the certificate chain at the
+//! signature's `x5u`.
+
+use crate::error::{Error, Result};
+use ring::signature::{UnparsedPublicKey, ECDSA_P384_SHA384_FIXED};
+use serde_json::Value;
+use std::fmt::Write;
+
+const SIGNATURE_PREFIX: &[u8] = b"Content-Signature:\x00";
+
+/// Verifies the records in a `/records` response body against the signature in
+/// a collection metadata response body.
+pub(crate) fn verify_content_signature(
+    records_payload: &str,
+    metadata_payload: &str,
+    public_key: &[u8],
+) -> Result<()> {
+    let records: Value = serde_json::from_str(records_payload)?;
+    let records = records
+        .get("data")
+        .and_then(Value::as_array)
+        .ok_or(Error::InvalidExperimentFormat)?;
+    let metadata: Value = serde_json::from_str(metadata_payload)?;
+    let metadata = &metadata["data"];
+    let last_modified = metadata["last_modified"]
+        .as_u64()
+        .ok_or_else(|| Error::SignatureError("missing collection timestamp".to_string()))?;
+    let signature = metadata["signature"]["signature"]
+        .as_str()
+        .ok_or_else(|| Error::SignatureError("missing signature".to_string()))?;
+    let signature = base64::decode_config(signature, base64::URL_SAFE)
+        .map_err(|e| Error::SignatureError(format!("invalid signature encoding: {}", e)))?;
+    UnparsedPublicKey::new(&ECDSA_P384_SHA384_FIXED, public_key)
+        .verify(&signed_message(records, last_modified), &signature)
+        .map_err(|_| Error::SignatureError("signature does not match the records".to_string()))
+}
+
+// The bytes the server signs: the canonical JSON of the records, sorted by id,
+// and the collection timestamp as a string.
+pub(crate) fn signed_message(records: &[Value], last_modified: u64) -> Vec<u8> {
+    let mut records = records.to_vec();
+    records.sort_by(|a, b| a["id"].as_str().cmp(&b["id"].as_str()));
+    let mut content = String::from(r#"{"data":["#);
+    for (i, record) in records.iter().enumerate() {
+        if i > 0 {
+            content.push(',');
+        }
+        write_canonical_json(record, &mut content);
+    }
+    write!(content, r#"],"last_modified":"{}"}}"#, last_modified).unwrap();
+    let mut message = SIGNATURE_PREFIX.to_vec();
+    message.extend_from_slice(content.as_bytes());
+    message
+}
+
+// Canonical JSON, as Remote Settings defines it: no whitespace, object keys
+// sorted, non-ASCII characters escaped, and floats that are whole numbers
+// written as integers.
+fn write_canonical_json(value: &Value, out: &mut String) {
+    match value {
+        Value::Null | Value::Bool(_) => out.push_str(&value.to_string()),
+        Value::Number(number) => match number.as_f64() {
+            Some(f) if number.is_f64() && f.fract() == 0.0 && f.abs() < 2f64.powi(53) => {
+                write!(out, "{}", f as i64).unwrap()
+            }
+            _ => out.push_str(&number.to_string()),
+        },
+        Value::String(s) => write_canonical_string(s, out),
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_json(value, out);
+            }
+            out.push(']');
+        }
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical_string(key, out);
+                out.push(':');
+                write_canonical_json(value, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_canonical_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_ascii() && c >= ' ' => out.push(c),
+            c => {
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    write!(out, "\\u{:04x}", unit).unwrap();
+                }
+            }
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P384_SHA384_FIXED_SIGNING};
+    use serde_json::json;
+
+    /// A locally generated signing key, standing in for the server's.
+    pub struct TestSigner {
+        key_pair: EcdsaKeyPair,
+    }
+
+    impl TestSigner {
+        pub fn new() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P384_SHA384_FIXED_SIGNING, &rng).unwrap();
+            let key_pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P384_SHA384_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+            Self { key_pair }
+        }
+
+        pub fn public_key(&self) -> &[u8] {
+            self.key_pair.public_key().as_ref()
+        }
+
+        /// Returns a collection metadata response body, signing the records
+        /// in the given `/records` response body.
+        pub fn metadata_payload(&self, records_payload: &str, last_modified: u64) -> String {
+            let records: Value = serde_json::from_str(records_payload).unwrap();
+            let message = signed_message(records["data"].as_array().unwrap(), last_modified);
+            let signature = self.key_pair.sign(&SystemRandom::new(), &message).unwrap();
+            json!({
+                "data": {
+                    "id": "messaging-experiments",
+                    "last_modified": last_modified,
+                    "signature": {
+                        "mode": "p384ecdsa",
+                        "signature": base64::encode_config(signature.as_ref(), base64::URL_SAFE),
+                    },
+                }
+            })
+            .to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_utils::TestSigner;
+    use super::*;
+    use serde_json::json;
+
+    fn canonical_json(value: Value) -> String {
+        let mut out = String::new();
+        write_canonical_json(&value, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_canonical_json() {
+        assert_eq!(
+            canonical_json(json!({"b": [1, 2.5, 3.0], "a": {"d": null, "c": true}})),
+            r#"{"a":{"c":true,"d":null},"b":[1,2.5,3]}"#
+        );
+        assert_eq!(
+            canonical_json(json!("quote \" backslash \\ tab \t nul \u{0} é 😀")),
+            r#""quote \" backslash \\ tab \t nul \u0000 \u00e9 \ud83d\ude00""#
+        );
+    }
+
+    #[test]
+    fn test_signed_message() {
+        let records = [json!({"id": "b", "x": 1}), json!({"id": "a"})];
+        assert_eq!(
+            signed_message(&records, 1234),
+            &b"Content-Signature:\x00{\"data\":[{\"id\":\"a\"},{\"id\":\"b\",\"x\":1}],\"last_modified\":\"1234\"}"[..]
+        );
+    }
+
+    #[test]
+    fn test_verify_content_signature() {
+        let signer = TestSigner::new();
+        let records = json!({"data": [{"id": "a", "slug": "a"}, {"id": "b", "slug": "b"}]});
+        let metadata = signer.metadata_payload(&records.to_string(), 1234);
+        assert!(
+            verify_content_signature(&records.to_string(), &metadata, signer.public_key()).is_ok()
+        );
+
+        // The order of the records, and of their keys, doesn't matter.
+        let reordered = r#"{"data": [{"slug": "b", "id": "b"}, {"id": "a", "slug": "a"}]}"#;
+        assert!(verify_content_signature(reordered, &metadata, signer.public_key()).is_ok());
+
+        let tampered = json!({"data": [{"id": "a", "slug": "a"}, {"id": "b", "slug": "c"}]});
+        assert!(matches!(
+            verify_content_signature(&tampered.to_string(), &metadata, signer.public_key()),
+            Err(Error::SignatureError(_))
+        ));
+
+        let other_signer = TestSigner::new();
+        assert!(matches!(
+            verify_content_signature(&records.to_string(), &metadata, other_signer.public_key()),
+            Err(Error::SignatureError(_))
+        ));
+
+        assert!(matches!(
+            verify_content_signature(&records.to_string(), r#"{"data": {}}"#, signer.public_key()),
+            Err(Error::SignatureError(_))
+        ));
+    }
+}
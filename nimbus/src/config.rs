@@ -12,9 +12,76 @@
 /// Currently includes the following:
 /// - `server_url`: The url for the settings server that would be used to retrieve experiments
 /// - `bucket_name`: The name of the bucket containing the collection on the server
+/// - `collection_name`: The name of the collection containing the experiments
+/// - `trust_root`: The base64-encoded public key (an uncompressed P-384 point) the
+///   collection's content signature is verified with, or `None` to not verify it
+/// - `retry_policy`: How failed requests are retried, or `None` for the default `RetryPolicy`
+/// - `additional_sources`: Other collections to fetch experiments from, which share the
+///   rest of this config. If several sources have an experiment with the same slug, the one
+///   from the collection above is used first, then the additional sources in order
 #[derive(Debug, Clone)]
 pub struct RemoteSettingsConfig {
     pub server_url: String,
     pub bucket_name: String,
     pub collection_name: String,
+    pub trust_root: Option<String>,
+    pub retry_policy: Option<RetryPolicy>,
+    pub additional_sources: Vec<RemoteSettingsSource>,
+}
+
+/// A collection to fetch experiments from, in addition to the one in the
+/// `RemoteSettingsConfig`. As with that, the `server_url` can be a `file://`
+/// URL of a directory, in which case the bucket and collection are ignored.
+#[derive(Debug, Clone)]
+pub struct RemoteSettingsSource {
+    pub server_url: String,
+    pub bucket_name: String,
+    pub collection_name: String,
+}
+
+/// How requests to the settings server which fail for what might be
+/// transient reasons, such as a 5xx response or a network error, are retried.
+/// Other failures, such as a 4xx response, are never retried, and neither are
+/// requests after the server asks us to back off.
+/// - `max_attempts`: The most times a request is made, including the first, so `1` means
+///   it is never retried
+/// - `base_delay_ms`: The delay before the first retry, which doubles for each retry after that
+/// - `max_jitter_ms`: The most random delay added to each retry, so that clients which failed
+///   at the same time don't all retry at the same time
+/// - `retryable_status_codes`: The HTTP status codes of the responses to retry
+/// - `retryable_errors`: The kinds of errors to retry requests which failed without a
+///   response for
+/// - `timeout_ms`: How long a request may take, including its retries. A retry isn't made
+///   if it would start after this, and each attempt is also limited by the network backend's
+///   own connect and read timeouts
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_jitter_ms: u64,
+    pub retryable_status_codes: Vec<u16>,
+    pub retryable_errors: Vec<RequestErrorKind>,
+    pub timeout_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 1000,
+            max_jitter_ms: 1000,
+            retryable_status_codes: vec![500, 502, 503, 504],
+            retryable_errors: vec![RequestErrorKind::Network],
+            timeout_ms: 30_000,
+        }
+    }
+}
+
+/// The kinds of errors a request can fail with before we get a response.
+/// - `Network`: We couldn't reach the server, eg because the device is offline
+/// - `Backend`: The network backend failed for some other reason
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RequestErrorKind {
+    Network,
+    Backend,
 }
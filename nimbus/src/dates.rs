@@ -0,0 +1,303 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The dates in an experiment's definition, eg its `startDate` and `endDate`.
+//!
+//! These are sent as either a plain date (`2021-01-31`, meaning the start of
+//! that day in UTC) or an RFC 3339 date and time (`2021-01-31T12:00:00Z`), and
+//! we keep them as the number of seconds since the Unix epoch so they can be
+//! compared with the current time.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: i64 = 24 * 3600;
+
+/// The current time, in seconds since the Unix epoch.
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Current date before Unix Epoch.")
+        .as_secs()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ExperimentDate(u64);
+
+impl ExperimentDate {
+    pub fn from_secs(secs: u64) -> Self {
+        Self(secs)
+    }
+
+    pub fn as_secs(&self) -> u64 {
+        self.0
+    }
+
+    /// Parses a plain date or an RFC 3339 date and time. Returns `None` if the
+    /// string is neither, or is before the Unix epoch.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (date, time) = match s.find(|c| c == 'T' || c == 't') {
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+            None => (s, None),
+        };
+        let days = parse_date(date)?;
+        let seconds = match time {
+            Some(time) => parse_time(time)?,
+            None => 0,
+        };
+        let secs = days * SECONDS_PER_DAY + seconds;
+        if secs < 0 {
+            return None;
+        }
+        Some(Self(secs as u64))
+    }
+}
+
+// Returns the number of days since the Unix epoch for a `YYYY-MM-DD` date.
+fn parse_date(s: &str) -> Option<i64> {
+    let mut parts = s.splitn(3, '-');
+    let year = parse_digits(parts.next()?, 4)?;
+    let month = parse_digits(parts.next()?, 2)?;
+    let day = parse_digits(parts.next()?, 2)?;
+    if month < 1 || month > 12 || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    Some(days_from_civil(year, month, day))
+}
+
+// Returns the number of seconds since midnight UTC for a `HH:MM:SS[.fff](Z|+HH:MM|-HH:MM)`
+// time, ignoring any fraction of a second.
+fn parse_time(s: &str) -> Option<i64> {
+    let (time, offset) = if let Some(time) = s.strip_suffix(|c| c == 'Z' || c == 'z') {
+        (time, 0)
+    } else {
+        let index = s.rfind(|c| c == '+' || c == '-')?;
+        let (time, offset) = s.split_at(index);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let mut parts = offset[1..].splitn(2, ':');
+        let hours = parse_digits(parts.next()?, 2)?;
+        let minutes = parse_digits(parts.next()?, 2)?;
+        if hours > 23 || minutes > 59 {
+            return None;
+        }
+        (time, sign * (hours * 3600 + minutes * 60))
+    };
+    let time = match time.find('.') {
+        Some(index) if time[index + 1..].chars().all(|c| c.is_ascii_digit()) => &time[..index],
+        Some(_) => return None,
+        None => time,
+    };
+    let mut parts = time.splitn(3, ':');
+    let hours = parse_digits(parts.next()?, 2)?;
+    let minutes = parse_digits(parts.next()?, 2)?;
+    let seconds = parse_digits(parts.next()?, 2)?;
+    // Allow for leap seconds.
+    if hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    Some(hours * 3600 + minutes * 60 + seconds - offset)
+}
+
+fn parse_digits(s: &str, len: usize) -> Option<i64> {
+    if s.len() != len || !s.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// The number of days between the Unix epoch and the given date, using
+// Howard Hinnant's `days_from_civil` algorithm
+// (http://howardhinnant.github.io/date_algorithms.html).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// The inverse of `days_from_civil`, returning `(year, month, day)`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Formats as an RFC 3339 date and time in UTC.
+impl fmt::Display for ExperimentDate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.0 as i64;
+        let (year, month, day) = civil_from_days(secs / SECONDS_PER_DAY);
+        let seconds = secs % SECONDS_PER_DAY;
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds % 3600 / 60,
+            seconds % 60
+        )
+    }
+}
+
+impl Serialize for ExperimentDate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ExperimentDate {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::parse(&s).ok_or_else(|| de::Error::custom(format!("invalid date: {}", s)))
+    }
+}
+
+/// Deserializes an optional date in an `Experiment`, treating a date we can't
+/// parse as no date. The experiments are persisted, so an unexpected date in
+/// one of them mustn't stop us from reading the others.
+pub(crate) fn deserialize_lenient<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<ExperimentDate>, D::Error> {
+    let value = match Option::<serde_json::Value>::deserialize(deserializer)? {
+        None | Some(serde_json::Value::Null) => return Ok(None),
+        Some(value) => value,
+    };
+    let date = value.as_str().and_then(ExperimentDate::parse);
+    if date.is_none() {
+        log::warn!("Ignoring invalid experiment date {}", value);
+    }
+    Ok(date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::*;
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(ExperimentDate::parse("1970-01-01").unwrap().as_secs(), 0);
+        assert_eq!(
+            ExperimentDate::parse("2021-01-31").unwrap().as_secs(),
+            1_612_051_200
+        );
+        assert_eq!(
+            ExperimentDate::parse("2020-02-29").unwrap().as_secs(),
+            1_582_934_400
+        );
+        assert_eq!(ExperimentDate::parse("2021-02-29"), None);
+        assert_eq!(ExperimentDate::parse("2021-13-01"), None);
+        assert_eq!(ExperimentDate::parse("2021-1-31"), None);
+        assert_eq!(ExperimentDate::parse("1969-12-31"), None);
+        assert_eq!(ExperimentDate::parse("not a date"), None);
+        assert_eq!(ExperimentDate::parse(""), None);
+    }
+
+    #[test]
+    fn test_parse_date_time() {
+        assert_eq!(
+            ExperimentDate::parse("2021-01-31T12:34:56Z")
+                .unwrap()
+                .as_secs(),
+            1_612_096_496
+        );
+        assert_eq!(
+            ExperimentDate::parse("2021-01-31T12:34:56.789Z"),
+            ExperimentDate::parse("2021-01-31T12:34:56Z")
+        );
+        assert_eq!(
+            ExperimentDate::parse("2021-01-31T14:34:56+02:00"),
+            ExperimentDate::parse("2021-01-31T12:34:56Z")
+        );
+        assert_eq!(
+            ExperimentDate::parse("2021-01-31T07:34:56-05:00"),
+            ExperimentDate::parse("2021-01-31T12:34:56Z")
+        );
+        assert_eq!(ExperimentDate::parse("2021-01-31T12:34:56"), None);
+        assert_eq!(ExperimentDate::parse("2021-01-31T25:00:00Z"), None);
+        assert_eq!(ExperimentDate::parse("2021-01-31T12:34Z"), None);
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for s in &[
+            "1970-01-01T00:00:00Z",
+            "2000-02-29T23:59:59Z",
+            "2021-01-31T12:34:56Z",
+            "2100-12-31T00:00:01Z",
+        ] {
+            assert_eq!(ExperimentDate::parse(s).unwrap().to_string(), *s);
+        }
+    }
+
+    #[test]
+    fn test_serde() {
+        let date: ExperimentDate = serde_json::from_str("\"2021-01-31\"").unwrap();
+        assert_eq!(
+            serde_json::to_string(&date).unwrap(),
+            "\"2021-01-31T00:00:00Z\""
+        );
+        assert!(serde_json::from_str::<ExperimentDate>("\"tomorrow\"").is_err());
+        assert!(serde_json::from_str::<ExperimentDate>("1612051200").is_err());
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Dates {
+        #[serde(default, deserialize_with = "deserialize_lenient")]
+        date: Option<ExperimentDate>,
+    }
+
+    #[test]
+    fn test_deserialize_lenient() {
+        let date = |json: &str| serde_json::from_str::<Dates>(json).unwrap().date;
+        assert_eq!(
+            date(r#"{"date": "2021-01-31T18:04:56.123456+05:30"}"#),
+            ExperimentDate::parse("2021-01-31T12:34:56Z")
+        );
+        assert_eq!(
+            date(r#"{"date": "2021-01-31T12:34:56.5-00:00"}"#),
+            ExperimentDate::parse("2021-01-31T12:34:56Z")
+        );
+        assert_eq!(
+            date(r#"{"date": "2021-01-31"}"#),
+            ExperimentDate::parse("2021-01-31T00:00:00Z")
+        );
+        // Dates we can't parse are ignored, rather than failing.
+        assert_eq!(date(r#"{"date": "31/01/2021"}"#), None);
+        assert_eq!(date(r#"{"date": "2021-01-31 12:34:56"}"#), None);
+        assert_eq!(date(r#"{"date": 1612051200}"#), None);
+        assert_eq!(date(r#"{"date": null}"#), None);
+        assert_eq!(date("{}"), None);
+    }
+}
@@ -2,9 +2,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::enrollment::get_enrollments;
+use crate::enrollment::{get_enrolled_feature_configs, get_enrollments, get_rollout_enrollments};
 use crate::error::{Error, Result};
 use crate::persistence::Database;
+use crate::{EnrolledFeatureConfig, ExposureEvent};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::sync::Mutex;
@@ -18,6 +19,8 @@ use std::sync::Mutex;
 // recreated every time the cache is updated.
 struct CachedData {
     pub experiment_branches: HashMap<String, String>,
+    pub enrollment_ids: HashMap<String, String>,
+    pub features: HashMap<String, EnrolledFeatureConfig>,
 }
 
 // This is the public cache API. Each NimbusClient can create one of these and
@@ -50,13 +53,26 @@ impl DatabaseCache {
     // &Database, which implies some mutex guarding that Database is held.
     pub fn update(&self, db: &Database) -> Result<()> {
         let experiments = get_enrollments(&db)?;
+        let rollouts = get_rollout_enrollments(&db)?;
         // Build the new hashmap.
-        let mut eb = HashMap::with_capacity(experiments.len());
-        for e in experiments {
+        let mut eb = HashMap::with_capacity(experiments.len() + rollouts.len());
+        let mut enrollment_ids = HashMap::with_capacity(experiments.len() + rollouts.len());
+        for e in experiments.into_iter().chain(rollouts) {
+            enrollment_ids.insert(e.slug.clone(), e.enrollment_id);
             eb.insert(e.slug, e.branch_slug);
         }
+        let mut features = HashMap::new();
+        for f in get_enrolled_feature_configs(db)? {
+            // These are ordered with experiments before rollouts, so an
+            // experiment wins over a rollout for the same feature, and then by
+            // slug, so we are at least deterministic should multiple
+            // experiments configure the same feature.
+            features.entry(f.feature_id.clone()).or_insert(f);
+        }
         let data = CachedData {
             experiment_branches: eb,
+            enrollment_ids,
+            features,
         };
         // then swap it in.
         let cell = self.data.lock().unwrap();
@@ -86,4 +102,23 @@ impl DatabaseCache {
     pub fn get_experiment_branch(&self, slug: &str) -> Result<Option<String>> {
         self.get_data(|data| data.experiment_branches.get(slug).cloned())
     }
+
+    pub fn get_feature_config(&self, feature_id: &str) -> Result<Option<EnrolledFeatureConfig>> {
+        self.get_data(|data| data.features.get(feature_id).cloned())
+    }
+
+    // The exposure event for the given feature, from the experiment (or
+    // rollout) whose feature config we use for it.
+    pub fn get_exposure_event(&self, feature_id: &str) -> Result<Option<ExposureEvent>> {
+        self.get_data(|data| {
+            let feature = data.features.get(feature_id)?;
+            let enrollment_id = data.enrollment_ids.get(&feature.experiment_slug)?;
+            Some(ExposureEvent {
+                feature_id: feature.feature_id.clone(),
+                experiment_slug: feature.experiment_slug.clone(),
+                branch_slug: feature.branch_slug.clone(),
+                enrollment_id: enrollment_id.clone(),
+            })
+        })
+    }
 }
@@ -1,19 +1,28 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
+use crate::dates::now_secs;
 use crate::evaluator::evaluate_enrollment;
 use crate::persistence::{Database, StoreId, Writer};
 use crate::{error::Result, Error};
-use crate::{AppContext, AvailableRandomizationUnits, EnrolledExperiment, Experiment};
+use crate::{
+    AvailableRandomizationUnits, EnrolledExperiment, EnrolledFeatureConfig, Experiment,
+    MatcherField, TargetingAttributes,
+};
 
 use ::uuid::Uuid;
 use serde_derive::*;
 use std::{
+    borrow::Cow,
     collections::{HashMap, HashSet},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::Duration,
 };
 
 const DB_KEY_GLOBAL_USER_PARTICIPATION: &str = "user-opt-in";
+const DB_KEY_PREVIEW_MODE: &str = "preview-mode";
+// The experiments we go back to when preview mode is switched off.
+pub(crate) const DB_KEY_EXPERIMENTS_BEFORE_PREVIEW: &str = "experiments-before-preview";
+const DB_KEY_EXPERIMENTS_FIRST_SEEN: &str = "experiments-first-seen";
 const DEFAULT_GLOBAL_USER_PARTICIPATION: bool = true;
 const PREVIOUS_ENROLLMENTS_GC_TIME: Duration = Duration::from_secs(30 * 24 * 3600);
 
@@ -23,6 +32,7 @@ const PREVIOUS_ENROLLMENTS_GC_TIME: Duration = Duration::from_secs(30 * 24 * 360
 pub enum EnrolledReason {
     Qualified, // A normal enrollment as per the experiment's rules.
     OptIn,     // Explicit opt-in.
+    Preview,   // Enrolled in preview mode, which we might not have been bucketed into.
 }
 
 // These are types we use internally for managing non-enrollments.
@@ -33,6 +43,11 @@ pub enum NotEnrolledReason {
     NotSelected, // The evaluator bucketing did not choose us.
     NotTargeted, // We are not being targeted for this experiment.
     EnrollmentsPaused, // The experiment enrollment is paused.
+    FeatureConflict, // We are already enrolled in another experiment for the same feature.
+    NotMatched { field: MatcherField }, // The experiment's matcher did not match the app context.
+    NotStarted,  // The experiment's start date has not been reached yet.
+    Ended,       // The experiment's end date has passed.
+    EnrollmentClosed, // The experiment's enrollment period (`proposed_enrollment`) is over.
 }
 
 // These are types we use internally for managing disqualifications.
@@ -44,6 +59,18 @@ pub enum DisqualifiedReason {
     NotTargeted, // The targeting has changed for an experiment.
 }
 
+impl DisqualifiedReason {
+    // The reason given in disqualification events, their telemetry and
+    // `EnrollmentState`s, so it mustn't change when a variant is renamed.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            DisqualifiedReason::Error => "error",
+            DisqualifiedReason::OptOut => "optout",
+            DisqualifiedReason::NotTargeted => "targeting",
+        }
+    }
+}
+
 // Every experiment has an ExperimentEnrollment, even when we aren't enrolled.
 // ⚠️ Warning : Altering this type might require a DB migration. ⚠️
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
@@ -59,7 +86,7 @@ impl ExperimentEnrollment {
         is_user_participating: bool,
         nimbus_id: &Uuid,
         available_randomization_units: &AvailableRandomizationUnits,
-        app_context: &AppContext,
+        targeting_attributes: &TargetingAttributes,
         experiment: &Experiment,
         out_enrollment_events: &mut Vec<EnrollmentChangeEvent>,
     ) -> Result<Self> {
@@ -81,7 +108,7 @@ impl ExperimentEnrollment {
             let enrollment = evaluate_enrollment(
                 nimbus_id,
                 available_randomization_units,
-                app_context,
+                targeting_attributes,
                 experiment,
             )?;
             log::debug!(
@@ -89,9 +116,7 @@ impl ExperimentEnrollment {
                 &enrollment.slug,
                 &enrollment
             );
-            if matches!(enrollment.status, EnrollmentStatus::Enrolled { .. }) {
-                out_enrollment_events.push(enrollment.get_change_event())
-            }
+            out_enrollment_events.extend(enrollment.get_change_event());
             enrollment
         })
     }
@@ -112,7 +137,7 @@ impl ExperimentEnrollment {
             slug: experiment.slug.clone(),
             status: EnrollmentStatus::new_enrolled(EnrolledReason::OptIn, branch_slug),
         };
-        out_enrollment_events.push(enrollment.get_change_event());
+        out_enrollment_events.extend(enrollment.get_change_event());
         Ok(enrollment)
     }
 
@@ -122,7 +147,7 @@ impl ExperimentEnrollment {
         is_user_participating: bool,
         nimbus_id: &Uuid,
         available_randomization_units: &AvailableRandomizationUnits,
-        app_context: &AppContext,
+        targeting_attributes: &TargetingAttributes,
         updated_experiment: &Experiment,
         out_enrollment_events: &mut Vec<EnrollmentChangeEvent>,
     ) -> Result<Self> {
@@ -134,7 +159,7 @@ impl ExperimentEnrollment {
                     let updated_enrollment = evaluate_enrollment(
                         nimbus_id,
                         available_randomization_units,
-                        app_context,
+                        targeting_attributes,
                         updated_experiment,
                     )?;
                     log::debug!(
@@ -143,9 +168,7 @@ impl ExperimentEnrollment {
                         &self,
                         updated_enrollment
                     );
-                    if matches!(updated_enrollment.status, EnrollmentStatus::Enrolled { .. }) {
-                        out_enrollment_events.push(updated_enrollment.get_change_event());
-                    }
+                    out_enrollment_events.extend(updated_enrollment.get_change_event());
                     updated_enrollment
                 }
             }
@@ -167,7 +190,7 @@ impl ExperimentEnrollment {
                             branch: branch.clone(),
                         },
                     };
-                    out_enrollment_events.push(updated_enrollment.get_change_event());
+                    out_enrollment_events.extend(updated_enrollment.get_change_event());
                     updated_enrollment
                 } else if !updated_experiment.has_branch(branch) {
                     // The branch we were in disappeared!
@@ -179,13 +202,21 @@ impl ExperimentEnrollment {
                             branch: branch.clone(),
                         },
                     };
-                    out_enrollment_events.push(updated_enrollment.get_change_event());
+                    out_enrollment_events.extend(updated_enrollment.get_change_event());
                     updated_enrollment
+                } else if updated_experiment.sticky_enrollment {
+                    // Once enrolled, changes to the targeting (or to the app
+                    // context) don't affect us.
+                    log::debug!(
+                        "Existing experiment enrollment '{}' is sticky, not re-evaluating",
+                        &self.slug
+                    );
+                    self.clone()
                 } else {
                     let evaluated_enrollment = evaluate_enrollment(
                         nimbus_id,
                         available_randomization_units,
-                        app_context,
+                        targeting_attributes,
                         updated_experiment,
                     )?;
                     match evaluated_enrollment.status {
@@ -198,11 +229,14 @@ impl ExperimentEnrollment {
                                     branch: branch.clone(),
                                 },
                             };
-                            out_enrollment_events.push(updated_enrollment.get_change_event());
+                            out_enrollment_events.extend(updated_enrollment.get_change_event());
                             updated_enrollment
                         }
                         EnrollmentStatus::NotEnrolled {
                             reason: NotEnrolledReason::NotTargeted,
+                        }
+                        | EnrollmentStatus::NotEnrolled {
+                            reason: NotEnrolledReason::NotMatched { .. },
                         } => {
                             log::debug!("Existing experiment enrollment '{}' is now disqualified (targeting change)", &self.slug);
                             let updated_enrollment = Self {
@@ -213,7 +247,7 @@ impl ExperimentEnrollment {
                                     branch: branch.clone(),
                                 },
                             };
-                            out_enrollment_events.push(updated_enrollment.get_change_event());
+                            out_enrollment_events.extend(updated_enrollment.get_change_event());
                             updated_enrollment
                         }
                         EnrollmentStatus::NotEnrolled { .. }
@@ -250,16 +284,18 @@ impl ExperimentEnrollment {
     }
 
     /// Transition our enrollment to WasEnrolled (Option::Some) or delete it (Option::None)
-    /// after an experiment has disappeared from the server.
+    /// after an experiment has disappeared from the server, or passed its end date.
+    /// `reason` is given in the unenrollment event, to tell these apart.
     ///
     /// If we transitioned to WasEnrolled, our enrollment will be garbage collected
     /// from the database after `PREVIOUS_ENROLLMENTS_GC_TIME`.
     fn on_experiment_ended(
         &self,
+        reason: Option<&str>,
         out_enrollment_events: &mut Vec<EnrollmentChangeEvent>,
     ) -> Option<Self> {
         log::debug!(
-            "Experiment '{}' vanished while we had enrollment status of {:?}",
+            "Experiment '{}' ended while we had enrollment status of {:?}",
             self.slug,
             self
         );
@@ -286,10 +322,87 @@ impl ExperimentEnrollment {
                 experiment_ended_at: now_secs(),
             },
         };
-        out_enrollment_events.push(enrollment.get_change_event());
+        out_enrollment_events.extend(enrollment.get_change_event().map(|event| {
+            EnrollmentChangeEvent {
+                reason: reason.map(str::to_owned),
+                ..event
+            }
+        }));
         Some(enrollment)
     }
 
+    /// Re-bucket an enrollment we only have because of preview mode, now that
+    /// it has been switched off. We stay enrolled if we would have been bucketed
+    /// into the experiment anyway, otherwise we unenroll, with a `"preview"`
+    /// reason in the event. As when evolving any other enrollment, we're
+    /// disqualified if the user has opted out, and unenrolled with an `"ended"`
+    /// reason if the experiment has ended.
+    ///
+    /// Like other unenrollments, we keep the enrollment id and branch in the
+    /// `WasEnrolled` status, so that we aren't enrolled again while the
+    /// experiment is on the server.
+    #[allow(clippy::too_many_arguments)]
+    fn on_preview_ended(
+        &self,
+        is_user_participating: bool,
+        nimbus_id: &Uuid,
+        available_randomization_units: &AvailableRandomizationUnits,
+        targeting_attributes: &TargetingAttributes,
+        experiment: &Experiment,
+        first_seen_at: u64,
+        out_enrollment_events: &mut Vec<EnrollmentChangeEvent>,
+    ) -> Result<Self> {
+        let (enrollment_id, branch) = match self.status {
+            EnrollmentStatus::Enrolled {
+                reason: EnrolledReason::Preview,
+                enrollment_id,
+                ref branch,
+            } => (enrollment_id, branch),
+            _ => return Ok(self.clone()),
+        };
+        if !is_user_participating {
+            return self.on_experiment_updated(
+                is_user_participating,
+                nimbus_id,
+                available_randomization_units,
+                targeting_attributes,
+                experiment,
+                out_enrollment_events,
+            );
+        }
+        let reason = if check_experiment_dates(experiment, first_seen_at, now_secs())
+            == Some(NotEnrolledReason::Ended)
+        {
+            "ended"
+        } else {
+            let evaluated_enrollment = evaluate_enrollment(
+                nimbus_id,
+                available_randomization_units,
+                targeting_attributes,
+                experiment,
+            )?;
+            if evaluated_enrollment.status.is_enrolled() {
+                return Ok(Self {
+                    slug: self.slug.clone(),
+                    status: EnrollmentStatus::Enrolled {
+                        enrollment_id,
+                        reason: EnrolledReason::Qualified,
+                        branch: branch.clone(),
+                    },
+                });
+            }
+            log::debug!(
+                "Experiment enrollment '{}' was only enrolled in preview mode, now {:?}",
+                &self.slug,
+                evaluated_enrollment
+            );
+            "preview"
+        };
+        Ok(self
+            .on_experiment_ended(Some(reason), out_enrollment_events)
+            .unwrap_or_else(|| self.clone()))
+    }
+
     /// Force unenroll ourselves from an experiment.
     fn on_explicit_opt_out(
         &self,
@@ -309,7 +422,7 @@ impl ExperimentEnrollment {
                         enrollment_id,
                     },
                 };
-                out_enrollment_events.push(enrollment.get_change_event());
+                out_enrollment_events.extend(enrollment.get_change_event());
                 enrollment
             }
             EnrollmentStatus::NotEnrolled { .. } => Self {
@@ -345,9 +458,9 @@ impl ExperimentEnrollment {
     }
 
     // Create a telemetry event describing the transition
-    // to the current enrollment state.
-    fn get_change_event(&self) -> EnrollmentChangeEvent {
-        match &self.status {
+    // to the current enrollment state, if it's one we report.
+    fn get_change_event(&self) -> Option<EnrollmentChangeEvent> {
+        Some(match &self.status {
             EnrollmentStatus::Enrolled {
                 enrollment_id,
                 branch,
@@ -379,15 +492,16 @@ impl ExperimentEnrollment {
                 &self.slug,
                 &enrollment_id,
                 &branch,
-                match reason {
-                    DisqualifiedReason::NotTargeted => Some("targeting"),
-                    DisqualifiedReason::OptOut => Some("optout"),
-                    DisqualifiedReason::Error => Some("error"),
-                },
+                Some(reason.as_str()),
                 EnrollmentChangeEventType::Disqualification,
             ),
-            EnrollmentStatus::NotEnrolled { .. } | EnrollmentStatus::Error { .. } => unreachable!(),
-        }
+            // We couldn't evaluate whether to enroll, so we never will.
+            EnrollmentStatus::Error { reason } => {
+                EnrollmentChangeEvent::enroll_failed(&self.slug, None, reason)
+            }
+            // Not being enrolled is the usual outcome, so isn't worth an event.
+            EnrollmentStatus::NotEnrolled { .. } => return None,
+        })
     }
 }
 // ⚠️ Warning : Altering this type might require a DB migration. ⚠️
@@ -427,16 +541,29 @@ impl EnrollmentStatus {
             enrollment_id: Uuid::new_v4(),
         }
     }
-    // This is used in examples, but not in the main dylib, and
-    // triggers a dead code warning when building with `--release`.
-    #[allow(dead_code)]
     pub fn is_enrolled(&self) -> bool {
         matches!(self, EnrollmentStatus::Enrolled { .. })
     }
 }
 
-/// Return information about all enrolled experiments.
+/// Return information about all enrolled experiments, not including rollouts.
 pub fn get_enrollments(db: &Database) -> Result<Vec<EnrolledExperiment>> {
+    get_enrollments_by_type(db, false)
+}
+
+/// Return information about all enrolled rollouts.
+pub fn get_rollout_enrollments(db: &Database) -> Result<Vec<EnrolledExperiment>> {
+    get_enrollments_by_type(db, true)
+}
+
+/// Return the enrollment state of every experiment we have an enrollment for,
+/// whether we are enrolled in it or not.
+pub fn get_enrollment_states(db: &Database) -> Result<Vec<EnrollmentState>> {
+    let enrollments: Vec<ExperimentEnrollment> = db.collect_all(StoreId::Enrollments)?;
+    Ok(enrollments.iter().map(EnrollmentState::from).collect())
+}
+
+fn get_enrollments_by_type(db: &Database, is_rollout: bool) -> Result<Vec<EnrolledExperiment>> {
     let enrollments: Vec<ExperimentEnrollment> = db.collect_all(StoreId::Enrollments)?;
     let mut result = Vec::with_capacity(enrollments.len());
     for enrollment in enrollments {
@@ -450,6 +577,9 @@ pub fn get_enrollments(db: &Database) -> Result<Vec<EnrolledExperiment>> {
             if let Some(experiment) =
                 db.get::<Experiment>(StoreId::Experiments, &enrollment.slug)?
             {
+                if experiment.is_rollout != is_rollout {
+                    continue;
+                }
                 result.push(EnrolledExperiment {
                     slug: experiment.slug,
                     user_facing_name: experiment.user_facing_name,
@@ -468,22 +598,65 @@ pub fn get_enrollments(db: &Database) -> Result<Vec<EnrolledExperiment>> {
     Ok(result)
 }
 
+/// Return the feature configs from the branches of all enrolled experiments
+/// and rollouts. The result is ordered with experiments before rollouts, as
+/// an experiment takes precedence over a rollout for the same feature, and
+/// then by slug.
+pub fn get_enrolled_feature_configs(db: &Database) -> Result<Vec<EnrolledFeatureConfig>> {
+    let enrollments: Vec<ExperimentEnrollment> = db.collect_all(StoreId::Enrollments)?;
+    let mut enrolled = Vec::new();
+    for enrollment in enrollments {
+        if let EnrollmentStatus::Enrolled { branch, .. } = enrollment.status {
+            if let Some(experiment) =
+                db.get::<Experiment>(StoreId::Experiments, &enrollment.slug)?
+            {
+                enrolled.push((experiment, branch));
+            }
+        }
+    }
+    enrolled.sort_by(|(a, _), (b, _)| {
+        a.is_rollout
+            .cmp(&b.is_rollout)
+            .then_with(|| a.slug.cmp(&b.slug))
+    });
+    let mut result = Vec::new();
+    for (experiment, branch) in enrolled {
+        let feature = experiment
+            .branches
+            .into_iter()
+            .find(|b| b.slug == branch)
+            .and_then(|b| b.feature);
+        if let Some(feature) = feature {
+            result.push(EnrolledFeatureConfig {
+                feature_id: feature.feature_id,
+                experiment_slug: experiment.slug,
+                branch_slug: branch,
+                enabled: feature.enabled,
+                value: feature
+                    .value
+                    .map(|v| serde_json::Value::Object(v).to_string()),
+            });
+        }
+    }
+    Ok(result)
+}
+
 pub(crate) struct EnrollmentsEvolver<'a> {
     nimbus_id: &'a Uuid,
     available_randomization_units: &'a AvailableRandomizationUnits,
-    app_context: &'a AppContext,
+    targeting_attributes: &'a TargetingAttributes,
 }
 
 impl<'a> EnrollmentsEvolver<'a> {
     pub(crate) fn new(
         nimbus_id: &'a Uuid,
         available_randomization_units: &'a AvailableRandomizationUnits,
-        app_context: &'a AppContext,
+        targeting_attributes: &'a TargetingAttributes,
     ) -> Self {
         Self {
             nimbus_id,
             available_randomization_units,
-            app_context,
+            targeting_attributes,
         }
     }
 
@@ -502,13 +675,39 @@ impl<'a> EnrollmentsEvolver<'a> {
         let existing_experiments = experiments_store.collect_all::<Experiment>(writer)?;
         let existing_enrollments =
             enrollments_store.collect_all::<ExperimentEnrollment>(&writer)?;
+        let experiments_first_seen =
+            update_experiments_first_seen(db, writer, updated_experiments)?;
+        // In preview mode, we evaluate the experiments as if everyone was in
+        // their bucket, but we store them unchanged.
+        let preview_mode = get_preview_mode(db, writer)?;
+        let evaluated_experiments: Cow<'_, [Experiment]> = if preview_mode {
+            Cow::Owned(updated_experiments.iter().map(without_bucketing).collect())
+        } else {
+            Cow::Borrowed(updated_experiments)
+        };
         // Calculate the changes.
-        let (updated_enrollments, enrollments_change_events) = self.evolve_enrollments(
+        let (mut updated_enrollments, enrollments_change_events) = self.evolve_enrollments(
             is_user_participating,
             &existing_experiments,
-            updated_experiments,
+            &evaluated_experiments,
             &existing_enrollments,
+            &experiments_first_seen,
         )?;
+        if preview_mode {
+            // Remember which enrollments we only have because of preview mode,
+            // so that they can be re-bucketed when it's switched off.
+            let existing_enrollments = map_enrollments(&existing_enrollments);
+            for enrollment in updated_enrollments.iter_mut() {
+                let was_enrolled = existing_enrollments
+                    .get(&enrollment.slug)
+                    .map_or(false, |existing| existing.status.is_enrolled());
+                if let EnrollmentStatus::Enrolled { reason, .. } = &mut enrollment.status {
+                    if !was_enrolled && *reason == EnrolledReason::Qualified {
+                        *reason = EnrolledReason::Preview;
+                    }
+                }
+            }
+        }
         let updated_enrollments = map_enrollments(&updated_enrollments);
         // Write the changes to the Database.
         enrollments_store.clear(writer)?;
@@ -528,15 +727,62 @@ impl<'a> EnrollmentsEvolver<'a> {
         Ok(enrollments_change_events)
     }
 
+    /// Re-buckets the enrollments we only have because of preview mode, once it
+    /// has been switched off, against the experiments in the database.
+    pub(crate) fn evolve_preview_enrollments_in_db(
+        &self,
+        db: &Database,
+        writer: &mut Writer,
+    ) -> Result<Vec<EnrollmentChangeEvent>> {
+        let is_user_participating = get_global_user_participation(db, writer)?;
+        let experiments_first_seen: HashMap<String, u64> = db
+            .get_store(StoreId::Meta)
+            .get(writer, DB_KEY_EXPERIMENTS_FIRST_SEEN)?
+            .unwrap_or_default();
+        let now = now_secs();
+        let experiments_store = db.get_store(StoreId::Experiments);
+        let enrollments_store = db.get_store(StoreId::Enrollments);
+        let mut enrollment_events = vec![];
+        for enrollment in enrollments_store.collect_all::<ExperimentEnrollment>(writer)? {
+            let experiment: Experiment = match experiments_store.get(writer, &enrollment.slug)? {
+                Some(experiment) => experiment,
+                None => continue,
+            };
+            let first_seen_at = experiments_first_seen
+                .get(&enrollment.slug)
+                .copied()
+                .unwrap_or(now);
+            let updated_enrollment = enrollment.on_preview_ended(
+                is_user_participating,
+                self.nimbus_id,
+                self.available_randomization_units,
+                self.targeting_attributes,
+                &experiment,
+                first_seen_at,
+                &mut enrollment_events,
+            )?;
+            if updated_enrollment != enrollment {
+                enrollments_store.put(writer, &updated_enrollment.slug, &updated_enrollment)?;
+            }
+        }
+        Ok(enrollment_events)
+    }
+
     /// Evolve and calculate the new set of enrollments, using the
     /// previous and current state of experiments and current enrollments.
+    ///
+    /// `experiments_first_seen` maps experiment slugs to the time (unix timestamp
+    /// in sec) we first saw them, which starts their enrollment period. Experiments
+    /// not in the map are treated as being seen for the first time now.
     pub(crate) fn evolve_enrollments(
         &self,
         is_user_participating: bool,
         existing_experiments: &[Experiment],
         updated_experiments: &[Experiment],
         existing_enrollments: &[ExperimentEnrollment],
+        experiments_first_seen: &HashMap<String, u64>,
     ) -> Result<(Vec<ExperimentEnrollment>, Vec<EnrollmentChangeEvent>)> {
+        let now = now_secs();
         let mut enrollment_events = vec![];
         let existing_experiments = map_experiments(&existing_experiments);
         let updated_experiments = map_experiments(&updated_experiments);
@@ -547,23 +793,127 @@ impl<'a> EnrollmentsEvolver<'a> {
         all_slugs.extend(updated_experiments.keys());
         all_slugs.extend(existing_enrollments.keys());
 
+        let was_enrolled = |slug: &str| {
+            existing_enrollments
+                .get(slug)
+                .map_or(false, |enrollment| enrollment.status.is_enrolled())
+        };
+        // Only one experiment, and one rollout, may be enrolled for any given
+        // feature. Experiments we are already enrolled in keep their features,
+        // so they are evolved first; the rest are evolved in slug order so
+        // that the outcome of feature conflicts is stable across runs.
+        let mut all_slugs: Vec<&String> = all_slugs.into_iter().collect();
+        all_slugs.sort_by(|a, b| was_enrolled(b).cmp(&was_enrolled(a)).then_with(|| a.cmp(b)));
+
+        let mut enrolled_experiment_features = HashSet::new();
+        let mut enrolled_rollout_features = HashSet::new();
         let mut updated_enrollments = Vec::with_capacity(all_slugs.len());
         for slug in all_slugs {
-            let updated_enrollment = self.evolve_enrollment(
-                is_user_participating,
-                existing_experiments.get(slug).copied(),
-                updated_experiments.get(slug).copied(),
-                existing_enrollments.get(slug).copied(),
-                &mut enrollment_events,
-            )?;
-            if let Some(enrollment) = updated_enrollment {
-                updated_enrollments.push(enrollment);
-            }
+            let num_events = enrollment_events.len();
+            let updated_experiment = updated_experiments.get(slug).copied();
+            let existing_enrollment = existing_enrollments.get(slug).copied();
+            let dates_enrollment = updated_experiment.and_then(|experiment| {
+                let first_seen_at = experiments_first_seen.get(slug).copied().unwrap_or(now);
+                self.evolve_enrollment_dates(
+                    is_user_participating,
+                    experiment,
+                    first_seen_at,
+                    now,
+                    existing_enrollment,
+                    &mut enrollment_events,
+                )
+            });
+            let updated_enrollment = match dates_enrollment {
+                Some(enrollment) => Some(enrollment),
+                None => self.evolve_enrollment(
+                    is_user_participating,
+                    existing_experiments.get(slug).copied(),
+                    updated_experiment,
+                    existing_enrollment,
+                    &mut enrollment_events,
+                )?,
+            };
+            let updated_enrollment = match (updated_enrollment, updated_experiments.get(slug)) {
+                (Some(enrollment), Some(experiment)) if enrollment.status.is_enrolled() => {
+                    let features = experiment.get_feature_ids();
+                    let enrolled_features = if experiment.is_rollout {
+                        &mut enrolled_rollout_features
+                    } else {
+                        &mut enrolled_experiment_features
+                    };
+                    if was_enrolled(slug) || features.is_disjoint(enrolled_features) {
+                        enrolled_features.extend(features);
+                        enrollment
+                    } else {
+                        log::debug!(
+                            "Experiment '{}' conflicts with the features of an existing enrollment",
+                            slug
+                        );
+                        // Forget the enrollment event we just generated.
+                        enrollment_events.truncate(num_events);
+                        ExperimentEnrollment {
+                            slug: slug.clone(),
+                            status: EnrollmentStatus::NotEnrolled {
+                                reason: NotEnrolledReason::FeatureConflict,
+                            },
+                        }
+                    }
+                }
+                (Some(enrollment), _) => enrollment,
+                (None, _) => continue,
+            };
+            updated_enrollments.push(updated_enrollment);
         }
 
         Ok((updated_enrollments, enrollment_events))
     }
 
+    /// Enforce the dates of an experiment, which take precedence over its targeting
+    /// and bucketing. Returns `None` if the dates don't affect the enrollment, in
+    /// which case it should be evolved as usual.
+    ///
+    /// Once an experiment's end date has passed we unenroll as though it had
+    /// disappeared from the server, but with an `"ended"` reason in the event.
+    /// Before its start date, or after its enrollment period, we don't enroll,
+    /// but existing enrollments are left alone.
+    fn evolve_enrollment_dates(
+        &self,
+        is_user_participating: bool,
+        experiment: &Experiment,
+        first_seen_at: u64,
+        now: u64,
+        existing_enrollment: Option<&ExperimentEnrollment>,
+        out_enrollment_events: &mut Vec<EnrollmentChangeEvent>,
+    ) -> Option<ExperimentEnrollment> {
+        let reason = check_experiment_dates(experiment, first_seen_at, now)?;
+        let not_enrolled = ExperimentEnrollment {
+            slug: experiment.slug.clone(),
+            status: EnrollmentStatus::NotEnrolled {
+                reason: reason.clone(),
+            },
+        };
+        if reason == NotEnrolledReason::Ended {
+            return Some(match existing_enrollment {
+                // We've already unenrolled.
+                Some(enrollment)
+                    if matches!(enrollment.status, EnrollmentStatus::WasEnrolled { .. }) =>
+                {
+                    enrollment.clone()
+                }
+                Some(enrollment) => enrollment
+                    .on_experiment_ended(Some("ended"), out_enrollment_events)
+                    .unwrap_or(not_enrolled),
+                None => not_enrolled,
+            });
+        }
+        match existing_enrollment.map(|enrollment| &enrollment.status) {
+            None | Some(EnrollmentStatus::NotEnrolled { .. }) if is_user_participating => {
+                Some(not_enrolled)
+            }
+            _ => None,
+        }
+    }
+
     /// Evolve a single enrollment using the previous and current state of an experiment.
     fn evolve_enrollment(
         &self,
@@ -580,13 +930,13 @@ impl<'a> EnrollmentsEvolver<'a> {
                     is_user_participating,
                     self.nimbus_id,
                     self.available_randomization_units,
-                    self.app_context,
+                    self.targeting_attributes,
                     experiment,
                     out_enrollment_events,
                 )?),
                 // Experiment deleted remotely.
                 (Some(_), None, Some(enrollment)) => {
-                    enrollment.on_experiment_ended(out_enrollment_events)
+                    enrollment.on_experiment_ended(None, out_enrollment_events)
                 }
                 // Known experiment.
                 (Some(_), Some(experiment), Some(enrollment)) => {
@@ -594,7 +944,7 @@ impl<'a> EnrollmentsEvolver<'a> {
                         is_user_participating,
                         self.nimbus_id,
                         self.available_randomization_units,
-                        self.app_context,
+                        self.targeting_attributes,
                         experiment,
                         out_enrollment_events,
                     )?)
@@ -632,9 +982,117 @@ fn map_enrollments(enrollments: &[ExperimentEnrollment]) -> HashMap<String, &Exp
     map_enrollments
 }
 
+// An `ExperimentEnrollment` in a form we can pass across the FFI, which can't
+// express enums with data, nor `Uuid`s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnrollmentState {
+    pub experiment_slug: String,
+    pub state: EnrollmentStateType,
+    // The branch and enrollment id, unless we were never enrolled.
+    pub branch_slug: Option<String>,
+    pub enrollment_id: Option<String>,
+    // A stable name for the reason for the state, eg `not_selected`, or
+    // `not_matched:locale` naming the matcher field, or the error message for
+    // `Error`. `WasEnrolled` has no reason.
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnrollmentStateType {
+    Enrolled,
+    NotEnrolled,
+    Disqualified,
+    WasEnrolled,
+    Error,
+}
+
+impl From<&ExperimentEnrollment> for EnrollmentState {
+    fn from(enrollment: &ExperimentEnrollment) -> Self {
+        let (state, branch, enrollment_id, reason) = match &enrollment.status {
+            EnrollmentStatus::Enrolled {
+                enrollment_id,
+                reason,
+                branch,
+            } => (
+                EnrollmentStateType::Enrolled,
+                Some(branch),
+                Some(enrollment_id),
+                Some(reason.state_reason().to_string()),
+            ),
+            EnrollmentStatus::NotEnrolled { reason } => (
+                EnrollmentStateType::NotEnrolled,
+                None,
+                None,
+                Some(reason.state_reason()),
+            ),
+            EnrollmentStatus::Disqualified {
+                enrollment_id,
+                reason,
+                branch,
+            } => (
+                EnrollmentStateType::Disqualified,
+                Some(branch),
+                Some(enrollment_id),
+                Some(reason.as_str().to_string()),
+            ),
+            EnrollmentStatus::WasEnrolled {
+                enrollment_id,
+                branch,
+                ..
+            } => (
+                EnrollmentStateType::WasEnrolled,
+                Some(branch),
+                Some(enrollment_id),
+                None,
+            ),
+            EnrollmentStatus::Error { reason } => {
+                (EnrollmentStateType::Error, None, None, Some(reason.clone()))
+            }
+        };
+        Self {
+            experiment_slug: enrollment.slug.clone(),
+            state,
+            branch_slug: branch.cloned(),
+            enrollment_id: enrollment_id.map(Uuid::to_string),
+            reason,
+        }
+    }
+}
+
+// The names of the reasons in an `EnrollmentState`. These are part of the
+// API, so unlike the `Debug` names, they mustn't change when a variant is renamed.
+impl EnrolledReason {
+    fn state_reason(&self) -> &'static str {
+        match self {
+            EnrolledReason::Qualified => "qualified",
+            EnrolledReason::OptIn => "opt_in",
+            EnrolledReason::Preview => "preview",
+        }
+    }
+}
+
+impl NotEnrolledReason {
+    fn state_reason(&self) -> String {
+        match self {
+            NotEnrolledReason::OptOut => "opt_out".to_string(),
+            NotEnrolledReason::NotSelected => "not_selected".to_string(),
+            NotEnrolledReason::NotTargeted => "not_targeted".to_string(),
+            NotEnrolledReason::EnrollmentsPaused => "enrollments_paused".to_string(),
+            NotEnrolledReason::FeatureConflict => "feature_conflict".to_string(),
+            NotEnrolledReason::NotMatched { field } => format!("not_matched:{}", field.as_str()),
+            NotEnrolledReason::NotStarted => "not_started".to_string(),
+            NotEnrolledReason::Ended => "ended".to_string(),
+            NotEnrolledReason::EnrollmentClosed => "enrollment_closed".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct EnrollmentChangeEvent {
     pub experiment_slug: String,
+    // Empty for `EnrollFailed` events, unless opting in to a branch failed.
     pub branch_slug: String,
+    // Empty for `EnrollFailed` events, which have no enrollment.
     pub enrollment_id: String,
     pub reason: Option<String>,
     pub change: EnrollmentChangeEventType,
@@ -656,13 +1114,28 @@ impl EnrollmentChangeEvent {
             change,
         }
     }
+
+    // We don't have an enrollment id when enrolling fails, and may not have a
+    // branch, so these are left empty.
+    pub(crate) fn enroll_failed(slug: &str, branch: Option<&str>, reason: &str) -> Self {
+        Self {
+            experiment_slug: slug.to_owned(),
+            branch_slug: branch.unwrap_or_default().to_owned(),
+            reason: Some(reason.to_owned()),
+            enrollment_id: String::new(),
+            change: EnrollmentChangeEventType::EnrollFailed,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum EnrollmentChangeEventType {
     Enrollment,
     Disqualification,
     Unenrollment,
+    // We tried to enroll, eg by evaluating the targeting or opting in, but
+    // couldn't. The `reason` says why.
+    EnrollFailed,
 }
 
 pub fn opt_in_with_branch(
@@ -715,17 +1188,91 @@ pub fn set_global_user_participation(
     store.put(writer, DB_KEY_GLOBAL_USER_PARTICIPATION, &opt_in)
 }
 
-fn now_secs() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Current date before Unix Epoch.")
-        .as_secs()
+/// Whether the user is a tester in preview mode, where they are enrolled in
+/// experiments regardless of bucketing, but targeting still applies.
+pub fn get_preview_mode(db: &Database, writer: &Writer) -> Result<bool> {
+    let store = db.get_store(StoreId::Meta);
+    Ok(store.get(writer, DB_KEY_PREVIEW_MODE)?.unwrap_or(false))
+}
+
+pub fn set_preview_mode(db: &Database, writer: &mut Writer, enabled: bool) -> Result<()> {
+    let store = db.get_store(StoreId::Meta);
+    store.put(writer, DB_KEY_PREVIEW_MODE, &enabled)
+}
+
+// Returns a copy of the experiment whose bucket contains everyone.
+fn without_bucketing(experiment: &Experiment) -> Experiment {
+    let mut experiment = experiment.clone();
+    experiment.bucket_config.start = 0;
+    experiment.bucket_config.count = experiment.bucket_config.total;
+    experiment
+}
+
+/// Checks whether the dates of an experiment stop us from enrolling in it at `now`,
+/// returning the reason if so.
+///
+/// The enrollment period is `proposed_enrollment` days long, and starts when we
+/// first saw the experiment, or at its start date if that is later. A
+/// `proposed_enrollment` of 0 means that enrollment never closes.
+fn check_experiment_dates(
+    experiment: &Experiment,
+    first_seen_at: u64,
+    now: u64,
+) -> Option<NotEnrolledReason> {
+    let start = experiment.start_date.map(|date| date.as_secs());
+    let end = experiment.end_date.map(|date| date.as_secs());
+    let enrollment_start = start.map_or(first_seen_at, |start| start.max(first_seen_at));
+    let enrollment_end = match experiment.proposed_enrollment {
+        0 => None,
+        days => Some(enrollment_start.saturating_add(u64::from(days) * 24 * 3600)),
+    };
+    if matches!(end, Some(end) if end <= now) {
+        Some(NotEnrolledReason::Ended)
+    } else if matches!(start, Some(start) if now < start) {
+        Some(NotEnrolledReason::NotStarted)
+    } else if matches!(enrollment_end, Some(enrollment_end) if enrollment_end <= now) {
+        Some(NotEnrolledReason::EnrollmentClosed)
+    } else {
+        None
+    }
+}
+
+/// Records when we first saw each of the `updated_experiments`, and forgets
+/// experiments which are no longer on the server, returning the updated record.
+/// The experiments we go back to when preview mode is switched off aren't
+/// forgotten, so that switching it on and off doesn't restart their enrollment
+/// period.
+fn update_experiments_first_seen(
+    db: &Database,
+    writer: &mut Writer,
+    updated_experiments: &[Experiment],
+) -> Result<HashMap<String, u64>> {
+    let store = db.get_store(StoreId::Meta);
+    let mut first_seen: HashMap<String, u64> = store
+        .get(writer, DB_KEY_EXPERIMENTS_FIRST_SEEN)?
+        .unwrap_or_default();
+    let experiments_before_preview: Vec<Experiment> = store
+        .get(writer, DB_KEY_EXPERIMENTS_BEFORE_PREVIEW)?
+        .unwrap_or_default();
+    let now = now_secs();
+    first_seen.retain(|slug, _| {
+        updated_experiments
+            .iter()
+            .chain(&experiments_before_preview)
+            .any(|e| &e.slug == slug)
+    });
+    for experiment in updated_experiments {
+        first_seen.entry(experiment.slug.clone()).or_insert(now);
+    }
+    store.put(writer, DB_KEY_EXPERIMENTS_FIRST_SEEN, &first_seen)?;
+    Ok(first_seen)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::persistence::{Database, StoreId};
+    use crate::{AppContext, ExperimentDate, Matcher, RandomizationUnit};
     use serde_json::json;
     use tempdir::TempDir;
 
@@ -794,30 +1341,31 @@ mod tests {
         db.collect_all::<ExperimentEnrollment>(StoreId::Enrollments)
     }
 
-    fn local_ctx() -> (Uuid, AppContext, AvailableRandomizationUnits) {
+    fn local_ctx() -> (Uuid, TargetingAttributes, AvailableRandomizationUnits) {
         // Use a fixed nimbus_id so we don't switch between branches.
         let nimbus_id = Uuid::parse_str("29686b11-00c0-4905-b5e4-f5f945eda60a").unwrap();
-        let app_ctx = AppContext {
+        let targeting_attributes = AppContext {
             app_id: "fenix".to_string(), // Matches the application in the experiments above.
             ..Default::default()
-        };
+        }
+        .into();
         let aru = Default::default();
-        (nimbus_id, app_ctx, aru)
+        (nimbus_id, targeting_attributes, aru)
     }
 
     fn enrollment_evolver<'a>(
         nimbus_id: &'a Uuid,
-        app_ctx: &'a AppContext,
+        targeting_attributes: &'a TargetingAttributes,
         aru: &'a AvailableRandomizationUnits,
     ) -> EnrollmentsEvolver<'a> {
-        EnrollmentsEvolver::new(nimbus_id, aru, app_ctx)
+        EnrollmentsEvolver::new(nimbus_id, aru, targeting_attributes)
     }
 
     #[test]
     fn test_evolver_new_experiment_enrolled() -> Result<()> {
         let exp = &get_test_experiments()[0];
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment = evolver
             .evolve_enrollment(true, None, Some(exp), None, &mut events)?
@@ -833,8 +1381,8 @@ mod tests {
     fn test_evolver_new_experiment_not_enrolled() -> Result<()> {
         let mut exp = get_test_experiments()[0].clone();
         exp.bucket_config.count = 0; // Make the experiment bucketing fail.
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment = evolver
             .evolve_enrollment(true, None, Some(&exp), None, &mut events)?
@@ -849,11 +1397,69 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_evolver_new_experiment_not_matched() -> Result<()> {
+        let mut exp = get_test_experiments()[0].clone();
+        exp.matcher = Some(Matcher {
+            app_min_version: Some("85.0".to_owned()),
+            ..Default::default()
+        });
+        let (nimbus_id, mut targeting_attributes, aru) = local_ctx();
+        targeting_attributes.app_context.app_version = Some("84.0.1".to_owned());
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let mut events = vec![];
+        let enrollment = evolver
+            .evolve_enrollment(true, None, Some(&exp), None, &mut events)?
+            .unwrap();
+        assert_eq!(
+            enrollment.status,
+            EnrollmentStatus::NotEnrolled {
+                reason: NotEnrolledReason::NotMatched {
+                    field: MatcherField::AppMinVersion
+                }
+            }
+        );
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_new_experiment_enroll_failed() -> Result<()> {
+        let mut exp = get_test_experiments()[0].clone();
+        // We don't have a client id to bucket with.
+        exp.bucket_config.randomization_unit = RandomizationUnit::ClientId;
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let mut events = vec![];
+        let enrollment = evolver
+            .evolve_enrollment(true, None, Some(&exp), None, &mut events)?
+            .unwrap();
+        assert!(matches!(enrollment.status, EnrollmentStatus::Error { .. }));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].experiment_slug, exp.slug);
+        assert_eq!(events[0].change, EnrollmentChangeEventType::EnrollFailed);
+        assert_eq!(events[0].reason.as_deref(), Some("No randomization unit"));
+        assert_eq!(events[0].enrollment_id, "");
+
+        // Experiments without any branches fail too, rather than failing the
+        // whole evolution.
+        let mut exp = get_test_experiments()[0].clone();
+        exp.branches.clear();
+        let mut events = vec![];
+        let enrollment = evolver
+            .evolve_enrollment(true, None, Some(&exp), None, &mut events)?
+            .unwrap();
+        assert!(matches!(enrollment.status, EnrollmentStatus::Error { .. }));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].change, EnrollmentChangeEventType::EnrollFailed);
+        Ok(())
+    }
+
     #[test]
     fn test_evolver_new_experiment_globally_opted_out() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment = evolver
             .evolve_enrollment(false, None, Some(&exp), None, &mut events)?
@@ -872,8 +1478,8 @@ mod tests {
     fn test_evolver_new_experiment_enrollment_paused() -> Result<()> {
         let mut exp = get_test_experiments()[0].clone();
         exp.is_enrollment_paused = true;
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment = evolver
             .evolve_enrollment(true, None, Some(&exp), None, &mut events)?
@@ -891,8 +1497,8 @@ mod tests {
     #[test]
     fn test_evolver_experiment_update_not_enrolled_opted_out() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let existing_enrollment = ExperimentEnrollment {
             slug: exp.slug.clone(),
@@ -918,8 +1524,8 @@ mod tests {
     fn test_evolver_experiment_update_not_enrolled_enrollment_paused() -> Result<()> {
         let mut exp = get_test_experiments()[0].clone();
         exp.is_enrollment_paused = true;
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let existing_enrollment = ExperimentEnrollment {
             slug: exp.slug.clone(),
@@ -945,8 +1551,8 @@ mod tests {
     fn test_evolver_experiment_update_not_enrolled_resuming_not_selected() -> Result<()> {
         let mut exp = get_test_experiments()[0].clone();
         exp.bucket_config.count = 0; // Make the experiment bucketing fail.
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let existing_enrollment = ExperimentEnrollment {
             slug: exp.slug.clone(),
@@ -976,8 +1582,8 @@ mod tests {
     #[test]
     fn test_evolver_experiment_update_not_enrolled_resuming_selected() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let existing_enrollment = ExperimentEnrollment {
             slug: exp.slug.clone(),
@@ -1006,8 +1612,8 @@ mod tests {
     #[test]
     fn test_evolver_experiment_update_enrolled_then_opted_out() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment_id = Uuid::new_v4();
         let existing_enrollment = ExperimentEnrollment {
@@ -1046,8 +1652,8 @@ mod tests {
     fn test_evolver_experiment_update_enrolled_then_experiment_paused() -> Result<()> {
         let mut exp = get_test_experiments()[0].clone();
         exp.is_enrollment_paused = true;
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment_id = Uuid::new_v4();
         let existing_enrollment = ExperimentEnrollment {
@@ -1087,9 +1693,9 @@ mod tests {
     #[test]
     fn test_evolver_experiment_update_enrolled_then_targeting_changed() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, mut app_ctx, aru) = local_ctx();
-        app_ctx.app_id = "foobar".to_owned(); // Make the experiment targeting fail.
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, mut targeting_attributes, aru) = local_ctx();
+        targeting_attributes.app_context.app_id = "foobar".to_owned(); // Make the experiment targeting fail.
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment_id = Uuid::new_v4();
         let existing_enrollment = ExperimentEnrollment {
@@ -1134,20 +1740,16 @@ mod tests {
     }
 
     #[test]
-    fn test_evolver_experiment_update_enrolled_then_bucketing_changed() -> Result<()> {
+    fn test_evolver_experiment_update_enrolled_sticky_then_targeting_changed() -> Result<()> {
         let mut exp = get_test_experiments()[0].clone();
-        exp.bucket_config.count = 0; // Make the experiment bucketing fail.
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        exp.sticky_enrollment = true;
+        exp.targeting = Some("locale == 'fr-FR'".to_owned()); // Make the experiment targeting fail.
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
-        let enrollment_id = Uuid::new_v4();
         let existing_enrollment = ExperimentEnrollment {
             slug: exp.slug.clone(),
-            status: EnrollmentStatus::Enrolled {
-                enrollment_id,
-                branch: "control".to_owned(),
-                reason: EnrolledReason::Qualified,
-            },
+            status: EnrollmentStatus::new_enrolled(EnrolledReason::Qualified, "control"),
         };
         let enrollment = evolver
             .evolve_enrollment(
@@ -1160,16 +1762,105 @@ mod tests {
             .unwrap();
         assert_eq!(enrollment, existing_enrollment);
         assert!(events.is_empty());
+
+        // Opting out still disqualifies us.
+        let enrollment = evolver
+            .evolve_enrollment(
+                false,
+                Some(&exp),
+                Some(&exp),
+                Some(&existing_enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert!(matches!(
+            enrollment.status,
+            EnrollmentStatus::Disqualified {
+                reason: DisqualifiedReason::OptOut,
+                ..
+            }
+        ));
+        assert_eq!(events.len(), 1);
         Ok(())
     }
 
     #[test]
-    fn test_evolver_experiment_update_enrolled_then_branches_changed() -> Result<()> {
+    fn test_evolver_experiment_update_enrolled_then_matcher_changed() -> Result<()> {
         let mut exp = get_test_experiments()[0].clone();
-        exp.branches = vec![
-            crate::Branch {
-                slug: "control".to_owned(),
-                ratio: 0,
+        exp.matcher = Some(Matcher {
+            locale: Some("fr-FR".to_owned()), // Make the experiment matcher fail.
+            ..Default::default()
+        });
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let mut events = vec![];
+        let enrollment_id = Uuid::new_v4();
+        let existing_enrollment = ExperimentEnrollment {
+            slug: exp.slug.clone(),
+            status: EnrollmentStatus::Enrolled {
+                enrollment_id,
+                branch: "control".to_owned(),
+                reason: EnrolledReason::Qualified,
+            },
+        };
+        let enrollment = evolver
+            .evolve_enrollment(
+                true,
+                Some(&exp),
+                Some(&exp),
+                Some(&existing_enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert!(matches!(
+            enrollment.status,
+            EnrollmentStatus::Disqualified {
+                reason: DisqualifiedReason::NotTargeted,
+                ..
+            }
+        ));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].reason, Some("targeting".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_experiment_update_enrolled_then_bucketing_changed() -> Result<()> {
+        let mut exp = get_test_experiments()[0].clone();
+        exp.bucket_config.count = 0; // Make the experiment bucketing fail.
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let mut events = vec![];
+        let enrollment_id = Uuid::new_v4();
+        let existing_enrollment = ExperimentEnrollment {
+            slug: exp.slug.clone(),
+            status: EnrollmentStatus::Enrolled {
+                enrollment_id,
+                branch: "control".to_owned(),
+                reason: EnrolledReason::Qualified,
+            },
+        };
+        let enrollment = evolver
+            .evolve_enrollment(
+                true,
+                Some(&exp),
+                Some(&exp),
+                Some(&existing_enrollment),
+                &mut events,
+            )?
+            .unwrap();
+        assert_eq!(enrollment, existing_enrollment);
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_experiment_update_enrolled_then_branches_changed() -> Result<()> {
+        let mut exp = get_test_experiments()[0].clone();
+        exp.branches = vec![
+            crate::Branch {
+                slug: "control".to_owned(),
+                ratio: 0,
                 feature: None,
             },
             crate::Branch {
@@ -1178,8 +1869,8 @@ mod tests {
                 feature: None,
             },
         ];
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment_id = Uuid::new_v4();
         let existing_enrollment = ExperimentEnrollment {
@@ -1212,8 +1903,8 @@ mod tests {
             ratio: 1,
             feature: None,
         }];
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment_id = Uuid::new_v4();
         let existing_enrollment = ExperimentEnrollment {
@@ -1254,8 +1945,8 @@ mod tests {
     #[test]
     fn test_evolver_experiment_update_disqualified_then_opted_out() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment_id = Uuid::new_v4();
         let existing_enrollment = ExperimentEnrollment {
@@ -1289,8 +1980,8 @@ mod tests {
     #[test]
     fn test_evolver_experiment_update_disqualified_then_bucketing_ok() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment_id = Uuid::new_v4();
         let existing_enrollment = ExperimentEnrollment {
@@ -1318,8 +2009,8 @@ mod tests {
     #[test]
     fn test_evolver_experiment_update_was_enrolled() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment_id = Uuid::new_v4();
         let existing_enrollment = ExperimentEnrollment {
@@ -1347,8 +2038,8 @@ mod tests {
     #[test]
     fn test_evolver_experiment_update_error() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let existing_enrollment = ExperimentEnrollment {
             slug: exp.slug.clone(),
@@ -1373,8 +2064,8 @@ mod tests {
     #[test]
     fn test_evolver_experiment_ended_was_enrolled() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment_id = Uuid::new_v4();
         let existing_enrollment = ExperimentEnrollment {
@@ -1416,8 +2107,8 @@ mod tests {
     #[test]
     fn test_evolver_experiment_ended_was_disqualified() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let enrollment_id = Uuid::new_v4();
         let existing_enrollment = ExperimentEnrollment {
@@ -1459,8 +2150,8 @@ mod tests {
     #[test]
     fn test_evolver_experiment_ended_was_not_enrolled() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let existing_enrollment = ExperimentEnrollment {
             slug: exp.slug.clone(),
@@ -1480,10 +2171,182 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_evolver_new_experiment_not_started() -> Result<()> {
+        let mut exp = get_test_experiments()[0].clone();
+        exp.start_date = Some(ExperimentDate::from_secs(now_secs() + 24 * 3600));
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &[], &[exp], &[], &HashMap::new())?;
+        assert_eq!(enrollments.len(), 1);
+        assert_eq!(
+            enrollments[0].status,
+            EnrollmentStatus::NotEnrolled {
+                reason: NotEnrolledReason::NotStarted
+            }
+        );
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_experiment_update_enrolled_then_end_date_passed() -> Result<()> {
+        let mut exp = get_test_experiments()[0].clone();
+        exp.end_date = Some(ExperimentDate::from_secs(now_secs() - 1));
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let enrollment_id = Uuid::new_v4();
+        let existing_enrollments = vec![ExperimentEnrollment {
+            slug: exp.slug.clone(),
+            status: EnrollmentStatus::Enrolled {
+                enrollment_id,
+                branch: "control".to_owned(),
+                reason: EnrolledReason::Qualified,
+            },
+        }];
+        let exps = vec![exp];
+        let (enrollments, events) = evolver.evolve_enrollments(
+            true,
+            &exps,
+            &exps,
+            &existing_enrollments,
+            &HashMap::new(),
+        )?;
+        assert_eq!(enrollments.len(), 1);
+        assert!(matches!(
+            enrollments[0].status,
+            EnrollmentStatus::WasEnrolled { .. }
+        ));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].enrollment_id, enrollment_id.to_string());
+        assert_eq!(events[0].change, EnrollmentChangeEventType::Unenrollment);
+        assert_eq!(events[0].reason.as_deref(), Some("ended"));
+
+        // The experiment lingering on the server doesn't change anything.
+        let (enrollments_again, events) =
+            evolver.evolve_enrollments(true, &exps, &exps, &enrollments, &HashMap::new())?;
+        assert_eq!(enrollments_again, enrollments);
+        assert!(events.is_empty());
+
+        // And we never enroll in it.
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &[], &exps, &[], &HashMap::new())?;
+        assert_eq!(
+            enrollments[0].status,
+            EnrollmentStatus::NotEnrolled {
+                reason: NotEnrolledReason::Ended
+            }
+        );
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_enrollment_closed() -> Result<()> {
+        let exps = vec![get_test_experiments()[0].clone()];
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        // We first saw the experiment longer ago than its enrollment period.
+        let mut first_seen = HashMap::new();
+        first_seen.insert(
+            exps[0].slug.clone(),
+            now_secs() - (exps[0].proposed_enrollment as u64 + 1) * 24 * 3600,
+        );
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &[], &exps, &[], &first_seen)?;
+        assert_eq!(
+            enrollments[0].status,
+            EnrollmentStatus::NotEnrolled {
+                reason: NotEnrolledReason::EnrollmentClosed
+            }
+        );
+        assert!(events.is_empty());
+
+        // Existing enrollments are unaffected.
+        let existing_enrollments = vec![ExperimentEnrollment {
+            slug: exps[0].slug.clone(),
+            status: EnrollmentStatus::new_enrolled(EnrolledReason::Qualified, "control"),
+        }];
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &exps, &exps, &existing_enrollments, &first_seen)?;
+        assert_eq!(enrollments, existing_enrollments);
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_experiment_dates() {
+        let mut exp = get_test_experiments()[0].clone();
+        let day = 24 * 3600;
+        let now = 100 * day;
+        assert_eq!(check_experiment_dates(&exp, now, now), None);
+        // The enrollment period is 7 days.
+        assert_eq!(check_experiment_dates(&exp, now - 6 * day, now), None);
+        assert_eq!(
+            check_experiment_dates(&exp, now - 7 * day, now),
+            Some(NotEnrolledReason::EnrollmentClosed)
+        );
+        // ...but it doesn't start before the start date.
+        exp.start_date = Some(ExperimentDate::from_secs(now - 2 * day));
+        assert_eq!(check_experiment_dates(&exp, now - 8 * day, now), None);
+        // An enrollment period of 0 days never closes.
+        exp.start_date = None;
+        exp.proposed_enrollment = 0;
+        assert_eq!(check_experiment_dates(&exp, now, now), None);
+        assert_eq!(check_experiment_dates(&exp, 0, now), None);
+        exp.start_date = Some(ExperimentDate::from_secs(now + day));
+        assert_eq!(
+            check_experiment_dates(&exp, now, now),
+            Some(NotEnrolledReason::NotStarted)
+        );
+        exp.start_date = None;
+        exp.end_date = Some(ExperimentDate::from_secs(now));
+        assert_eq!(
+            check_experiment_dates(&exp, now, now),
+            Some(NotEnrolledReason::Ended)
+        );
+    }
+
+    #[test]
+    fn test_experiments_first_seen() -> Result<()> {
+        let tmp_dir = TempDir::new("test_experiments_first_seen")?;
+        let db = Database::new(&tmp_dir)?;
+        let exps = get_test_experiments();
+        let mut writer = db.write()?;
+        let first_seen = update_experiments_first_seen(&db, &mut writer, &exps)?;
+        assert_eq!(first_seen.len(), 2);
+
+        // Pretend we saw the first experiment a while ago.
+        let mut first_seen_earlier = first_seen.clone();
+        first_seen_earlier.insert(exps[0].slug.clone(), 1234);
+        db.get_store(StoreId::Meta).put(
+            &mut writer,
+            DB_KEY_EXPERIMENTS_FIRST_SEEN,
+            &first_seen_earlier,
+        )?;
+        let first_seen = update_experiments_first_seen(&db, &mut writer, &exps)?;
+        assert_eq!(first_seen, first_seen_earlier);
+
+        // Experiments we'll go back to after preview mode are remembered.
+        let meta_store = db.get_store(StoreId::Meta);
+        meta_store.put(&mut writer, DB_KEY_EXPERIMENTS_BEFORE_PREVIEW, &exps)?;
+        let first_seen = update_experiments_first_seen(&db, &mut writer, &exps[1..])?;
+        assert_eq!(first_seen, first_seen_earlier);
+
+        // Experiments that disappear are forgotten.
+        meta_store.delete(&mut writer, DB_KEY_EXPERIMENTS_BEFORE_PREVIEW)?;
+        let first_seen = update_experiments_first_seen(&db, &mut writer, &exps[1..])?;
+        assert_eq!(first_seen.len(), 1);
+        assert!(first_seen.contains_key(&exps[1].slug));
+        writer.commit()?;
+        Ok(())
+    }
+
     #[test]
     fn test_evolver_garbage_collection_before_threshold() -> Result<()> {
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let existing_enrollment = ExperimentEnrollment {
             slug: "secure-gold".to_owned(),
@@ -1502,8 +2365,8 @@ mod tests {
 
     #[test]
     fn test_evolver_garbage_collection_after_threshold() -> Result<()> {
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let mut events = vec![];
         let existing_enrollment = ExperimentEnrollment {
             slug: "secure-gold".to_owned(),
@@ -1531,8 +2394,8 @@ mod tests {
                 experiment_ended_at: now_secs(),
             },
         };
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let res = evolver.evolve_enrollment(
             true,
             None,
@@ -1546,8 +2409,8 @@ mod tests {
     #[test]
     fn test_evolver_existing_experiment_has_no_enrollment() {
         let exp = get_test_experiments()[0].clone();
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         let res = evolver.evolve_enrollment(true, Some(&exp), Some(&exp), None, &mut vec![]);
         assert!(res.is_err());
     }
@@ -1555,13 +2418,179 @@ mod tests {
     #[test]
     #[should_panic]
     fn test_evolver_no_experiments_no_enrollment() {
-        let (nimbus_id, app_ctx, aru) = local_ctx();
-        let evolver = enrollment_evolver(&nimbus_id, &app_ctx, &aru);
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
         evolver
             .evolve_enrollment(true, None, None, None, &mut vec![])
             .unwrap();
     }
 
+    fn get_feature_conflict_test_experiments() -> Vec<Experiment> {
+        // Both experiments enroll everyone, and configure the same feature.
+        get_test_experiments()
+            .into_iter()
+            .map(|mut exp| {
+                for branch in exp.branches.iter_mut() {
+                    branch.feature = Some(crate::FeatureConfig {
+                        feature_id: "about_welcome".to_owned(),
+                        enabled: true,
+                        value: None,
+                    });
+                }
+                exp
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_evolver_feature_conflict() -> Result<()> {
+        let exps = get_feature_conflict_test_experiments();
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &[], &exps, &[], &HashMap::new())?;
+        let enrollments = map_enrollments(&enrollments);
+        // "secure-gold" sorts first, so it wins the feature.
+        assert!(enrollments["secure-gold"].status.is_enrolled());
+        assert_eq!(
+            enrollments["secure-silver"].status,
+            EnrollmentStatus::NotEnrolled {
+                reason: NotEnrolledReason::FeatureConflict
+            }
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].experiment_slug, "secure-gold");
+        assert_eq!(events[0].change, EnrollmentChangeEventType::Enrollment);
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_feature_conflict_existing_enrollment_wins() -> Result<()> {
+        let exps = get_feature_conflict_test_experiments();
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        // We are already enrolled in "secure-silver", and "secure-gold" is new.
+        let existing_enrollments = vec![ExperimentEnrollment {
+            slug: "secure-silver".to_owned(),
+            status: EnrollmentStatus::new_enrolled(EnrolledReason::Qualified, "control"),
+        }];
+        let (enrollments, events) = evolver.evolve_enrollments(
+            true,
+            &exps[1..],
+            &exps,
+            &existing_enrollments,
+            &HashMap::new(),
+        )?;
+        let enrollments = map_enrollments(&enrollments);
+        assert_eq!(enrollments["secure-silver"], &existing_enrollments[0]);
+        assert_eq!(
+            enrollments["secure-gold"].status,
+            EnrollmentStatus::NotEnrolled {
+                reason: NotEnrolledReason::FeatureConflict
+            }
+        );
+        assert!(events.is_empty());
+
+        // Once "secure-silver" ends, "secure-gold" can claim the feature.
+        let existing_enrollments: Vec<ExperimentEnrollment> =
+            enrollments.values().map(|e| (*e).clone()).collect();
+        let (enrollments, events) = evolver.evolve_enrollments(
+            true,
+            &exps,
+            &exps[..1],
+            &existing_enrollments,
+            &HashMap::new(),
+        )?;
+        let enrollments = map_enrollments(&enrollments);
+        assert!(enrollments["secure-gold"].status.is_enrolled());
+        assert!(matches!(
+            enrollments["secure-silver"].status,
+            EnrollmentStatus::WasEnrolled { .. }
+        ));
+        assert_eq!(events.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_no_feature_conflict_for_different_features() -> Result<()> {
+        let mut exps = get_feature_conflict_test_experiments();
+        for branch in exps[1].branches.iter_mut() {
+            branch.feature.as_mut().unwrap().feature_id = "other_feature".to_owned();
+        }
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &[], &exps, &[], &HashMap::new())?;
+        assert_eq!(enrollments.len(), 2);
+        assert!(enrollments.iter().all(|e| e.status.is_enrolled()));
+        assert_eq!(events.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_rollout_no_feature_conflict_with_experiment() -> Result<()> {
+        let mut exps = get_feature_conflict_test_experiments();
+        exps[1].is_rollout = true;
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &[], &exps, &[], &HashMap::new())?;
+        assert_eq!(enrollments.len(), 2);
+        assert!(enrollments.iter().all(|e| e.status.is_enrolled()));
+        assert_eq!(events.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_evolver_rollouts_feature_conflict() -> Result<()> {
+        let mut exps = get_feature_conflict_test_experiments();
+        for exp in exps.iter_mut() {
+            exp.is_rollout = true;
+        }
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let (enrollments, events) =
+            evolver.evolve_enrollments(true, &[], &exps, &[], &HashMap::new())?;
+        let enrollments = map_enrollments(&enrollments);
+        assert!(enrollments["secure-gold"].status.is_enrolled());
+        assert_eq!(
+            enrollments["secure-silver"].status,
+            EnrollmentStatus::NotEnrolled {
+                reason: NotEnrolledReason::FeatureConflict
+            }
+        );
+        assert_eq!(events.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollout_enrollments() -> Result<()> {
+        let tmp_dir = TempDir::new("test_rollout_enrollments")?;
+        let db = Database::new(&tmp_dir)?;
+        // "secure-gold" sorts first, so make it the rollout to check that the
+        // experiment still wins the feature.
+        let mut exps = get_feature_conflict_test_experiments();
+        exps[0].is_rollout = true;
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let mut writer = db.write()?;
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        writer.commit()?;
+
+        let experiments = get_enrollments(&db)?;
+        assert_eq!(experiments.len(), 1);
+        assert_eq!(experiments[0].slug, "secure-silver");
+        let rollouts = get_rollout_enrollments(&db)?;
+        assert_eq!(rollouts.len(), 1);
+        assert_eq!(rollouts[0].slug, "secure-gold");
+
+        let features = get_enrolled_feature_configs(&db)?;
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0].experiment_slug, "secure-silver");
+        assert_eq!(features[1].experiment_slug, "secure-gold");
+        Ok(())
+    }
+
     #[test]
     fn test_enrollment_explicit_opt_in() -> Result<()> {
         let exp = get_test_experiments()[0].clone();
@@ -1670,14 +2699,15 @@ mod tests {
         let exp1 = get_test_experiments()[0].clone();
         let nimbus_id = Uuid::new_v4();
         let aru = Default::default();
-        let app_ctx = AppContext {
+        let targeting_attributes: TargetingAttributes = AppContext {
             app_id: "fenix".to_string(),
             ..Default::default()
-        };
+        }
+        .into();
         assert_eq!(get_enrollments(&db)?.len(), 0);
         let mut writer = db.write()?;
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &targeting_attributes);
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &[exp1])?;
         writer.commit()?;
 
@@ -1730,6 +2760,94 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_enrollment_states() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_enrollment_states")?;
+        let db = Database::new(&tmp_dir)?;
+        let mut exps = get_test_experiments();
+        exps[1].bucket_config.count = 0; // Make the experiment bucketing fail.
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+        let mut writer = db.write()?;
+        evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        writer.commit()?;
+
+        let enrolled = get_enrollments(&db)?;
+        let mut states = get_enrollment_states(&db)?;
+        states.sort_by(|a, b| a.experiment_slug.cmp(&b.experiment_slug));
+        assert_eq!(
+            states,
+            vec![
+                EnrollmentState {
+                    experiment_slug: exps[0].slug.clone(),
+                    state: EnrollmentStateType::Enrolled,
+                    branch_slug: Some(enrolled[0].branch_slug.clone()),
+                    enrollment_id: Some(enrolled[0].enrollment_id.clone()),
+                    reason: Some("qualified".to_string()),
+                },
+                EnrollmentState {
+                    experiment_slug: exps[1].slug.clone(),
+                    state: EnrollmentStateType::NotEnrolled,
+                    branch_slug: None,
+                    enrollment_id: None,
+                    reason: Some("not_selected".to_string()),
+                },
+            ]
+        );
+
+        opt_out(&db, &exps[0].slug)?;
+        let state = get_enrollment_states(&db)?
+            .into_iter()
+            .find(|s| s.experiment_slug == exps[0].slug)
+            .unwrap();
+        assert_eq!(state.state, EnrollmentStateType::Disqualified);
+        assert_eq!(state.reason.as_deref(), Some("optout"));
+        assert_eq!(state.enrollment_id, Some(enrolled[0].enrollment_id.clone()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_enrollment_state_reasons() {
+        let state = |status| {
+            EnrollmentState::from(&ExperimentEnrollment {
+                slug: "secure-gold".to_string(),
+                status,
+            })
+        };
+        let not_matched = state(EnrollmentStatus::NotEnrolled {
+            reason: NotEnrolledReason::NotMatched {
+                field: MatcherField::AppMinVersion,
+            },
+        });
+        assert_eq!(not_matched.state, EnrollmentStateType::NotEnrolled);
+        assert_eq!(
+            not_matched.reason.as_deref(),
+            Some("not_matched:appMinVersion")
+        );
+
+        let error = state(EnrollmentStatus::Error {
+            reason: "No randomization unit".to_string(),
+        });
+        assert_eq!(error.state, EnrollmentStateType::Error);
+        assert_eq!(error.branch_slug, None);
+        assert_eq!(error.reason.as_deref(), Some("No randomization unit"));
+
+        let disqualified = state(EnrollmentStatus::Disqualified {
+            enrollment_id: Uuid::new_v4(),
+            reason: DisqualifiedReason::Error,
+            branch: "control".to_string(),
+        });
+        assert_eq!(disqualified.state, EnrollmentStateType::Disqualified);
+        assert_eq!(disqualified.reason.as_deref(), Some("error"));
+
+        let preview = state(EnrollmentStatus::new_enrolled(
+            EnrolledReason::Preview,
+            "control",
+        ));
+        assert_eq!(preview.reason.as_deref(), Some("preview"));
+    }
+
     #[test]
     fn test_updates() -> Result<()> {
         let _ = env_logger::try_init();
@@ -1737,15 +2855,16 @@ mod tests {
         let db = Database::new(&tmp_dir)?;
         let nimbus_id = Uuid::new_v4();
         let aru = Default::default();
-        let app_ctx = AppContext {
+        let targeting_attributes: TargetingAttributes = AppContext {
             app_id: "fenix".to_string(),
             ..Default::default()
-        };
+        }
+        .into();
         assert_eq!(get_enrollments(&db)?.len(), 0);
         let exps = get_test_experiments();
         let mut writer = db.write()?;
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &targeting_attributes);
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
         writer.commit()?;
 
@@ -1756,7 +2875,7 @@ mod tests {
         let mut writer = db.write()?;
         // pretend we just updated from the server and one of the 2 is missing.
         let exps = &[exps[1].clone()];
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &targeting_attributes);
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, exps)?;
         writer.commit()?;
 
@@ -1774,6 +2893,152 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_preview_mode_ignores_bucketing() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_preview_mode_ignores_bucketing")?;
+        let db = Database::new(&tmp_dir)?;
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let mut exps = get_test_experiments();
+        // Nobody is in the bucket for the first experiment.
+        exps[0].bucket_config.count = 0;
+        let evolver = enrollment_evolver(&nimbus_id, &targeting_attributes, &aru);
+
+        let mut writer = db.write()?;
+        let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        writer.commit()?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].experiment_slug, "secure-silver");
+
+        let mut writer = db.write()?;
+        set_preview_mode(&db, &mut writer, true)?;
+        let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        writer.commit()?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].experiment_slug, "secure-gold");
+        assert_eq!(get_enrollments(&db)?.len(), 2);
+
+        // Only the new enrollment is remembered as being made by preview mode.
+        let enrollment: ExperimentEnrollment =
+            db.get(StoreId::Enrollments, "secure-gold")?.unwrap();
+        assert!(matches!(
+            enrollment.status,
+            EnrollmentStatus::Enrolled {
+                reason: EnrolledReason::Preview,
+                ..
+            }
+        ));
+        let enrollment: ExperimentEnrollment =
+            db.get(StoreId::Enrollments, "secure-silver")?.unwrap();
+        assert!(matches!(
+            enrollment.status,
+            EnrollmentStatus::Enrolled {
+                reason: EnrolledReason::Qualified,
+                ..
+            }
+        ));
+
+        // The experiment is stored with its real bucket config.
+        let stored: Experiment = db.get(StoreId::Experiments, "secure-gold")?.unwrap();
+        assert_eq!(stored.bucket_config.count, 0);
+
+        // Targeting still applies.
+        exps[0].targeting = Some("locale == 'fr-FR'".to_owned()); // Make the targeting fail.
+        let mut writer = db.write()?;
+        let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
+        writer.commit()?;
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0].change,
+            EnrollmentChangeEventType::Disqualification
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_on_preview_ended() -> Result<()> {
+        let mut exp = get_test_experiments()[0].clone();
+        // Nobody is in the bucket for the experiment.
+        exp.bucket_config.count = 0;
+        let (nimbus_id, targeting_attributes, aru) = local_ctx();
+        let enrollment_id = Uuid::new_v4();
+        let enrollment = ExperimentEnrollment {
+            slug: exp.slug.clone(),
+            status: EnrollmentStatus::Enrolled {
+                enrollment_id,
+                branch: "control".to_owned(),
+                reason: EnrolledReason::Preview,
+            },
+        };
+        let now = now_secs();
+
+        // We remember the enrollment we're leaving, like the event does.
+        let mut events = vec![];
+        let updated = enrollment.on_preview_ended(
+            true,
+            &nimbus_id,
+            &aru,
+            &targeting_attributes,
+            &exp,
+            now,
+            &mut events,
+        )?;
+        assert!(matches!(
+            &updated.status,
+            EnrollmentStatus::WasEnrolled { enrollment_id: id, branch, .. }
+                if *id == enrollment_id && branch == "control"
+        ));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].enrollment_id, enrollment_id.to_string());
+        assert_eq!(events[0].reason.as_deref(), Some("preview"));
+
+        // Users who opted out are disqualified, as they would be otherwise.
+        let mut events = vec![];
+        let updated = enrollment.on_preview_ended(
+            false,
+            &nimbus_id,
+            &aru,
+            &targeting_attributes,
+            &exp,
+            now,
+            &mut events,
+        )?;
+        assert!(matches!(
+            updated.status,
+            EnrollmentStatus::Disqualified {
+                reason: DisqualifiedReason::OptOut,
+                ..
+            }
+        ));
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].change,
+            EnrollmentChangeEventType::Disqualification
+        );
+
+        // Even if we would be bucketed into it, an experiment which has ended
+        // is unenrolled.
+        exp.bucket_config.count = exp.bucket_config.total;
+        exp.end_date = Some(ExperimentDate::from_secs(now - 1));
+        let mut events = vec![];
+        let updated = enrollment.on_preview_ended(
+            true,
+            &nimbus_id,
+            &aru,
+            &targeting_attributes,
+            &exp,
+            now,
+            &mut events,
+        )?;
+        assert!(matches!(
+            updated.status,
+            EnrollmentStatus::WasEnrolled { .. }
+        ));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].reason.as_deref(), Some("ended"));
+        Ok(())
+    }
+
     #[test]
     fn test_global_opt_out() -> Result<()> {
         let _ = env_logger::try_init();
@@ -1781,10 +3046,11 @@ mod tests {
         let db = Database::new(&tmp_dir)?;
         let mut writer = db.write()?;
         let nimbus_id = Uuid::new_v4();
-        let app_ctx = AppContext {
+        let targeting_attributes: TargetingAttributes = AppContext {
             app_id: "fenix".to_string(),
             ..Default::default()
-        };
+        }
+        .into();
         let aru = Default::default();
         assert_eq!(get_enrollments(&db)?.len(), 0);
         let exps = get_test_experiments();
@@ -1792,7 +3058,7 @@ mod tests {
         // User has opted out of new experiments.
         set_global_user_participation(&db, &mut writer, false)?;
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &targeting_attributes);
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
         writer.commit()?;
 
@@ -1818,7 +3084,7 @@ mod tests {
         let mut writer = db.write()?;
         set_global_user_participation(&db, &mut writer, true)?;
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &targeting_attributes);
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
         writer.commit()?;
 
@@ -1837,7 +3103,7 @@ mod tests {
         let mut writer = db.write()?;
         set_global_user_participation(&db, &mut writer, false)?;
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &targeting_attributes);
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
         writer.commit()?;
 
@@ -1863,7 +3129,7 @@ mod tests {
         let mut writer = db.write()?;
         set_global_user_participation(&db, &mut writer, true)?;
 
-        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &app_ctx);
+        let evolver = EnrollmentsEvolver::new(&nimbus_id, &aru, &targeting_attributes);
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &exps)?;
         writer.commit()?;
 
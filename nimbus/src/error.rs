@@ -51,6 +51,10 @@ pub enum Error {
     BackoffError(u64),
     #[error("Initialization of the database is not yet complete")]
     DatabaseNotReady,
+    #[error("Content signature verification failed: {0}")]
+    SignatureError(String),
+    #[error("Error watching the experiments for changes: {0}")]
+    WatchError(String),
 }
 
 // This can be replaced with #[from] in the enum definition
@@ -10,7 +10,7 @@ use crate::{
     error::{Error, Result},
     AvailableRandomizationUnits,
 };
-use crate::{matcher::AppContext, sampling};
+use crate::{matcher::TargetingAttributes, sampling, versioning};
 use crate::{Branch, Experiment};
 use jexl_eval::Evaluator;
 use serde_derive::*;
@@ -31,6 +31,7 @@ impl Bucket {
 ///
 /// - `nimbus_id` The auto-generated nimbus_id
 /// - `available_randomization_units`: The app provded available randomization units
+/// - `targeting_attributes`: The app context and custom attributes to target against
 /// - `experiment` - The experiment.
 ///
 /// An `ExperimentEnrollment` -  you need to inspect the EnrollmentStatus to
@@ -44,9 +45,10 @@ impl Bucket {
 pub fn evaluate_enrollment(
     nimbus_id: &Uuid,
     available_randomization_units: &AvailableRandomizationUnits,
-    app_context: &AppContext,
+    targeting_attributes: &TargetingAttributes,
     exp: &Experiment,
 ) -> Result<ExperimentEnrollment> {
+    let app_context = &targeting_attributes.app_context;
     // Verify the application-id matches the application being targeted
     // by the experiment.
     if !exp.application.eq(&app_context.app_id) {
@@ -58,10 +60,22 @@ pub fn evaluate_enrollment(
         });
     }
 
+    // Check the simple, declarative targeting before evaluating any JEXL.
+    if let Some(matcher) = &exp.matcher {
+        if let Some(field) = matcher.find_mismatch(app_context) {
+            return Ok(ExperimentEnrollment {
+                slug: exp.slug.clone(),
+                status: EnrollmentStatus::NotEnrolled {
+                    reason: NotEnrolledReason::NotMatched { field },
+                },
+            });
+        }
+    }
+
     // Get targeting out of the way - "if let chains" are experimental,
     // otherwise we could improve this.
     if let Some(expr) = &exp.targeting {
-        if let Some(status) = targeting(expr, app_context) {
+        if let Some(status) = targeting(expr, targeting_attributes) {
             return Ok(ExperimentEnrollment {
                 slug: exp.slug.clone(),
                 status,
@@ -82,10 +96,19 @@ pub fn evaluate_enrollment(
                         bucket_config.count,
                         bucket_config.total,
                     )? {
-                        EnrollmentStatus::new_enrolled(
-                            EnrolledReason::Qualified,
-                            &choose_branch(&exp.slug, &exp.branches, &id)?.clone().slug,
-                        )
+                        match choose_branch(&exp.slug, &exp.branches, &id) {
+                            Ok(branch) => EnrollmentStatus::new_enrolled(
+                                EnrolledReason::Qualified,
+                                &branch.slug,
+                            ),
+                            // eg, there are no branches, or their ratios are all zero.
+                            Err(e) => {
+                                log::warn!("Could not choose a branch for {}: {}", &exp.slug, e);
+                                EnrollmentStatus::Error {
+                                    reason: e.to_string(),
+                                }
+                            }
+                        }
                     } else {
                         EnrollmentStatus::NotEnrolled {
                             reason: NotEnrolledReason::NotSelected,
@@ -93,8 +116,6 @@ pub fn evaluate_enrollment(
                     }
                 }
                 None => {
-                    // XXX: When we link in glean, it would be nice if we could emit
-                    // a failure telemetry event here.
                     log::info!(
                         "Could not find a suitable randomization unit for {}. Skipping experiment.",
                         &exp.slug
@@ -141,11 +162,11 @@ fn choose_branch<'a>(slug: &str, branches: &'a [Branch], id: &str) -> Result<&'a
 
 /// Checks if the client is targeted by an experiment
 /// This api evaluates the JEXL statement retrieved from the server
-/// against the application context provided by the client
+/// against the application context and custom attributes provided by the client
 ///
 /// # Arguments
 /// - `expression_statement`: The JEXL statement provided by the server
-/// - `ctx`: The application context provided by the client
+/// - `targeting_attributes`: The application context and custom attributes provided by the client
 ///
 /// If this app can not be targeted, returns an EnrollmentStatus to indicate
 /// why. Returns None if we should continue to evaluate the enrollment status.
@@ -155,10 +176,24 @@ fn choose_branch<'a>(slug: &str, branches: &'a [Branch], id: &str) -> Result<&'a
 /// cases (But not limited to):
 /// - The `expression_statement` is not a valid JEXL statement
 /// - The `expression_statement` expects fields that do not exist in the AppContext definition
+///   or the custom attributes
 /// - The result of evaluating the statement against the context is not a boolean
 /// - jexl-rs returned an error
-fn targeting(expression_statement: &str, ctx: &AppContext) -> Option<EnrollmentStatus> {
-    match Evaluator::new().eval_in_context(expression_statement, ctx.clone()) {
+fn targeting(
+    expression_statement: &str,
+    targeting_attributes: &TargetingAttributes,
+) -> Option<EnrollmentStatus> {
+    let context = match targeting_attributes.to_jexl_context() {
+        Ok(context) => context,
+        Err(e) => {
+            return Some(EnrollmentStatus::Error {
+                reason: e.to_string(),
+            })
+        }
+    };
+    let evaluator =
+        Evaluator::new().with_transform("versionCompare", versioning::version_compare_transform);
+    match evaluator.eval_in_context(expression_statement, context) {
         Ok(res) => match res.as_bool() {
             Some(true) => None,
             Some(false) => Some(EnrollmentStatus::NotEnrolled {
@@ -177,7 +212,7 @@ fn targeting(expression_statement: &str, ctx: &AppContext) -> Option<EnrollmentS
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{BucketConfig, Experiment, RandomizationUnit};
+    use crate::{AppContext, BucketConfig, Experiment, RandomizationUnit};
 
     #[test]
     fn test_targeting() {
@@ -199,7 +234,7 @@ mod tests {
             android_sdk_version: Some("29".to_string()),
             debug_tag: None,
         };
-        assert_eq!(targeting(expression_statement, &ctx), None);
+        assert_eq!(targeting(expression_statement, &ctx.into()), None);
 
         // A matching context testing the logical OR of the expression
         let ctx = AppContext {
@@ -215,7 +250,7 @@ mod tests {
             android_sdk_version: Some("29".to_string()),
             debug_tag: None,
         };
-        assert_eq!(targeting(expression_statement, &ctx), None);
+        assert_eq!(targeting(expression_statement, &ctx.into()), None);
 
         // A non-matching context testing the logical AND of the expression
         let non_matching_ctx = AppContext {
@@ -232,7 +267,7 @@ mod tests {
             debug_tag: None,
         };
         assert!(matches!(
-            targeting(expression_statement, &non_matching_ctx),
+            targeting(expression_statement, &non_matching_ctx.into()),
             Some(EnrollmentStatus::NotEnrolled {
                 reason: NotEnrolledReason::NotTargeted
             })
@@ -253,7 +288,7 @@ mod tests {
             debug_tag: None,
         };
         assert!(matches!(
-            targeting(expression_statement, &non_matching_ctx),
+            targeting(expression_statement, &non_matching_ctx.into()),
             Some(EnrollmentStatus::NotEnrolled {
                 reason: NotEnrolledReason::NotTargeted
             })
@@ -273,6 +308,74 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_version_targeting() {
+        let expression_statement = "app_version|versionCompare('85.0') >= 0";
+        let ctx = |version: &str| AppContext {
+            app_version: Some(version.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(targeting(expression_statement, &ctx("85.0").into()), None);
+        assert_eq!(targeting(expression_statement, &ctx("85.0.1").into()), None);
+        // A plain string comparison would get this one wrong.
+        assert_eq!(targeting(expression_statement, &ctx("100.0").into()), None);
+        for version in &["9.0", "84.0", "85.0a1", "85.0b2"] {
+            assert_eq!(
+                targeting(expression_statement, &ctx(*version).into()),
+                Some(EnrollmentStatus::NotEnrolled {
+                    reason: NotEnrolledReason::NotTargeted
+                })
+            );
+        }
+
+        // A missing version can't be compared.
+        assert!(matches!(
+            targeting(expression_statement, &Default::default()),
+            Some(EnrollmentStatus::Error { .. })
+        ));
+    }
+
+    #[test]
+    fn test_custom_targeting_attributes() {
+        let expression_statement = "is_first_run && days_since_install < 7 && locale == 'en-US'";
+        let app_context = AppContext {
+            locale: Some("en-US".to_string()),
+            ..Default::default()
+        };
+        let custom = |value: serde_json::Value| match value {
+            serde_json::Value::Object(custom) => custom,
+            _ => unreachable!(),
+        };
+
+        let attributes = TargetingAttributes::new(
+            app_context.clone(),
+            custom(serde_json::json!({"is_first_run": true, "days_since_install": 2})),
+        );
+        assert_eq!(targeting(expression_statement, &attributes), None);
+
+        let attributes = TargetingAttributes::new(
+            app_context.clone(),
+            custom(serde_json::json!({"is_first_run": true, "days_since_install": 10})),
+        );
+        assert_eq!(
+            targeting(expression_statement, &attributes),
+            Some(EnrollmentStatus::NotEnrolled {
+                reason: NotEnrolledReason::NotTargeted
+            })
+        );
+
+        // Custom attributes can't override the app context.
+        let attributes = TargetingAttributes::new(
+            app_context,
+            custom(serde_json::json!({
+                "is_first_run": true,
+                "days_since_install": 2,
+                "locale": "de-DE"
+            })),
+        );
+        assert_eq!(targeting(expression_statement, &attributes), None);
+    }
+
     #[test]
     fn test_evaluation_error() {
         // This is an invalid JEXL statement
@@ -362,10 +465,11 @@ mod tests {
         // Tested against the desktop implementation
         let id = uuid::Uuid::parse_str("299eed1e-be6d-457d-9e53-da7b1a03f10d").unwrap();
         // Application context for matching exp3
-        let context = AppContext {
+        let context: TargetingAttributes = AppContext {
             app_id: "org.example.app".to_string(),
             ..Default::default()
-        };
+        }
+        .into();
 
         let enrollment =
             evaluate_enrollment(&id, &available_randomization_units, &context, &experiment1)
@@ -9,46 +9,88 @@ mod evaluator;
 pub use error::{Error, Result};
 mod client;
 mod config;
+mod dates;
 mod matcher;
+mod observer;
 mod persistence;
 mod sampling;
+mod telemetry;
 mod updating;
+mod variables;
+mod versioning;
 #[cfg(debug_assertions)]
 pub use evaluator::evaluate_enrollment;
 
-use client::{create_client, parse_experiments, SettingsClient};
-pub use config::RemoteSettingsConfig;
+#[cfg(feature = "fs-watch")]
+pub use client::ExperimentsWatcher;
+use client::{
+    create_client, create_preview_client, parse_experiments, FetchedExperiments, SettingsClient,
+    SettingsClientState,
+};
+pub use client::{RejectedExperiment, RejectionReason, RemoteMetadata};
+pub use config::{RemoteSettingsConfig, RemoteSettingsSource, RequestErrorKind, RetryPolicy};
+pub use dates::ExperimentDate;
 use dbcache::DatabaseCache;
-pub use enrollment::EnrollmentStatus;
 use enrollment::{
-    get_enrollments, get_global_user_participation, opt_in_with_branch, opt_out,
-    set_global_user_participation, EnrollmentChangeEvent, EnrollmentChangeEventType,
-    EnrollmentsEvolver,
+    get_enrollment_states, get_enrollments, get_global_user_participation, get_preview_mode,
+    get_rollout_enrollments, opt_in_with_branch, opt_out, set_global_user_participation,
+    set_preview_mode, EnrollmentsEvolver, DB_KEY_EXPERIMENTS_BEFORE_PREVIEW,
+};
+pub use enrollment::{
+    EnrollmentChangeEvent, EnrollmentChangeEventType, EnrollmentState, EnrollmentStateType,
+    EnrollmentStatus,
 };
-pub use matcher::AppContext;
+pub use matcher::{AppContext, Matcher, MatcherField, TargetingAttributes};
+pub use observer::EnrollmentObserver;
+use observer::EnrollmentObservers;
 use once_cell::sync::OnceCell;
 use persistence::{Database, StoreId, Writer};
 use serde_derive::*;
-use std::path::PathBuf;
-use std::sync::Mutex;
-use updating::{read_and_remove_pending_experiments, write_pending_experiments};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use telemetry::TelemetryObserver;
+pub use telemetry::{JsonLinesTelemetrySink, MemoryTelemetrySink, TelemetryEvent, TelemetrySink};
+use updating::{
+    has_pending_experiments, read_and_remove_pending_experiments, write_pending_experiments,
+};
 use uuid::Uuid;
+pub use variables::FeatureVariables;
+pub use versioning::compare_versions;
 
 const DEFAULT_TOTAL_BUCKETS: u32 = 10000;
 const DB_KEY_NIMBUS_ID: &str = "nimbus-id";
+const DB_KEY_SETTINGS_CLIENT_STATE: &str = "settings-client-state";
+const DB_KEY_PREVIEW_SETTINGS_CLIENT_STATE: &str = "preview-settings-client-state";
+const DB_KEY_HAS_FETCHED: &str = "has-fetched";
+const DB_KEY_BUNDLED_EXPERIMENTS_APPLIED: &str = "bundled-experiments-applied";
+const DB_KEY_REJECTED_EXPERIMENTS: &str = "rejected-experiments";
 
 /// Nimbus is the main struct representing the experiments state
 /// It should hold all the information needed to communicate a specific user's
 /// experimentation status
 pub struct NimbusClient {
-    settings_client: Mutex<Box<dyn SettingsClient + Send>>,
+    // The clients aren't behind a lock, so that one request waiting to be
+    // retried doesn't hold up the others.
+    settings_client: Box<dyn SettingsClient + Send + Sync>,
+    // Fetches from the preview collection instead, when in preview mode.
+    preview_settings_client: Box<dyn SettingsClient + Send + Sync>,
     available_randomization_units: AvailableRandomizationUnits,
-    app_context: AppContext,
-    db: OnceCell<Mutex<Database>>,
+    targeting_attributes: Mutex<TargetingAttributes>,
+    // Shared with the thread watching the experiments, if any.
+    db: Arc<OnceCell<Mutex<Database>>>,
     // Manages an in-memory cache so that we can answer certain requests
     // without doing (or waiting for) IO.
     database_cache: DatabaseCache,
     db_path: PathBuf,
+    // Experiments bundled with the app, to use until we've fetched from the
+    // server: either their JSON, or the path of a file containing it.
+    bundled_experiments: Option<String>,
+    enrollment_observers: EnrollmentObservers,
+    // The enrollments we've recorded an exposure for, so that we only do so
+    // once per enrollment while the client lives.
+    exposed_enrollment_ids: Mutex<HashSet<String>>,
 }
 
 impl NimbusClient {
@@ -59,22 +101,72 @@ impl NimbusClient {
         db_path: P,
         config: Option<RemoteSettingsConfig>,
         available_randomization_units: AvailableRandomizationUnits,
+        bundled_experiments: Option<String>,
     ) -> Result<Self> {
-        let settings_client = Mutex::new(create_client(config)?);
+        let preview_settings_client = create_preview_client(config.clone())?;
+        let settings_client = create_client(config)?;
         Ok(Self {
             settings_client,
+            preview_settings_client,
             available_randomization_units,
-            app_context,
+            targeting_attributes: Mutex::new(app_context.into()),
             database_cache: Default::default(),
             db_path: db_path.into(),
-            db: OnceCell::default(),
+            db: Default::default(),
+            bundled_experiments,
+            enrollment_observers: Default::default(),
+            exposed_enrollment_ids: Default::default(),
         })
     }
 
     pub fn initialize(&self) -> Result<()> {
         let db = self.db()?.lock().unwrap();
+        let events = self.apply_bundled_experiments(&db)?;
         self.database_cache.update(&db)?;
-        Ok(())
+        self.notify_enrollment_observers(db, &events)
+    }
+
+    // If we've never had experiments from elsewhere, enrolls in the
+    // experiments bundled with the app instead, so that first-run experiments
+    // work before we're online. We only do this once; the experiments from the
+    // first fetch (or `set_experiments_locally()`) then replace the bundled ones.
+    fn apply_bundled_experiments(&self, db: &Database) -> Result<Vec<EnrollmentChangeEvent>> {
+        let bundled_experiments = match &self.bundled_experiments {
+            Some(bundled_experiments) => bundled_experiments,
+            None => return Ok(vec![]),
+        };
+        let mut writer = db.write()?;
+        let meta_store = db.get_store(StoreId::Meta);
+        let has_fetched: Option<bool> = meta_store.get(&writer, DB_KEY_HAS_FETCHED)?;
+        let bundle_applied: Option<bool> =
+            meta_store.get(&writer, DB_KEY_BUNDLED_EXPERIMENTS_APPLIED)?;
+        if has_fetched.unwrap_or(false)
+            || bundle_applied.unwrap_or(false)
+            || has_pending_experiments(db, &writer)?
+        {
+            return Ok(vec![]);
+        }
+        // A broken bundle shouldn't stop Nimbus from working, so we carry on
+        // without it.
+        let experiments = match read_bundled_experiments(bundled_experiments) {
+            Ok(fetched) => fetched.experiments,
+            Err(e) => {
+                log::error!("Failed to read the bundled experiments: {}", e);
+                return Ok(vec![]);
+            }
+        };
+        log::info!("applying {} bundled experiments", experiments.len());
+        let nimbus_id = self.read_or_create_nimbus_id(db, &mut writer)?;
+        let targeting_attributes = self.targeting_attributes.lock().unwrap().clone();
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &self.available_randomization_units,
+            &targeting_attributes,
+        );
+        let events = evolver.evolve_enrollments_in_db(db, &mut writer, &experiments)?;
+        meta_store.put(&mut writer, DB_KEY_BUNDLED_EXPERIMENTS_APPLIED, &true)?;
+        writer.commit()?;
+        Ok(events)
     }
 
     // Note: the contract for this function is that it never blocks on IO.
@@ -82,15 +174,79 @@ impl NimbusClient {
         self.database_cache.get_experiment_branch(&slug)
     }
 
-    pub fn get_experiment_branches(&self, slug: String) -> Result<Vec<Branch>> {
+    pub fn get_experiment_branches(&self, slug: String) -> Result<Vec<ExperimentBranch>> {
         Ok(self
             .get_all_experiments()?
-            .iter()
+            .into_iter()
             .find(|e| e.slug == slug)
-            .map(|e| e.branches.clone())
+            .map(|e| e.branches.into_iter().map(ExperimentBranch::from).collect())
             .ok_or(Error::NoSuchExperiment(slug))?)
     }
 
+    // Note: the contract for this function is that it never blocks on IO.
+    pub fn get_feature_config(&self, feature_id: String) -> Result<Option<EnrolledFeatureConfig>> {
+        self.database_cache.get_feature_config(&feature_id)
+    }
+
+    /// Returns the variables (ie, the `value` of the `FeatureConfig`) for the
+    /// given feature, taken from the branch of the experiment we are enrolled
+    /// in. Returns `None` if we aren't enrolled in an experiment for this
+    /// feature, or if the branch doesn't configure any variables.
+    ///
+    /// Like `get_feature_config()`, this never blocks on IO.
+    pub fn get_feature_variables(&self, feature_id: &str) -> Result<Option<FeatureVariables>> {
+        let value = self
+            .database_cache
+            .get_feature_config(feature_id)?
+            .and_then(|feature| feature.value);
+        Ok(match value {
+            Some(value) => Some(FeatureVariables::new(serde_json::from_str(&value)?)),
+            None => None,
+        })
+    }
+
+    // The same as `get_feature_variables()`, but JSON-encoded so that the
+    // variables can be passed across the FFI.
+    pub fn get_feature_config_variables(&self, feature_id: String) -> Result<Option<String>> {
+        Ok(self
+            .database_cache
+            .get_feature_config(&feature_id)?
+            .and_then(|feature| feature.value))
+    }
+
+    /// Records that the user was exposed to the given feature, ie that they
+    /// actually encountered it, for the experiment (or rollout) whose feature
+    /// config we use for it. The `ExposureEvent` is passed to the enrollment
+    /// observers the first time this is called for an enrollment while the
+    /// client lives, so later calls, and calls for features we aren't enrolled
+    /// in an experiment for, do nothing.
+    ///
+    /// Like `get_feature_config()`, this never blocks on IO.
+    pub fn record_exposure(&self, feature_id: String) -> Result<()> {
+        let event = match self.database_cache.get_exposure_event(&feature_id)? {
+            Some(event) => event,
+            None => {
+                log::debug!("Not enrolled in an experiment for '{}'", feature_id);
+                return Ok(());
+            }
+        };
+        if !self
+            .exposed_enrollment_ids
+            .lock()
+            .unwrap()
+            .insert(event.enrollment_id.clone())
+        {
+            return Ok(());
+        }
+        log::info!(
+            "recording exposure to '{}' for experiment '{}'",
+            feature_id,
+            event.experiment_slug
+        );
+        self.enrollment_observers.notify_exposure(&event);
+        Ok(())
+    }
+
     pub fn get_global_user_participation(&self) -> Result<bool> {
         // This is a bit smelly, but get_global_user_participation() needs a
         // writer so that the implementation of update_enrollments can pass one
@@ -114,22 +270,150 @@ impl NimbusClient {
         // We pass the existing experiments as "updated experiments"
         // to the evolver.
         let nimbus_id = self.read_or_create_nimbus_id(&db, &mut writer)?;
+        let targeting_attributes = self.targeting_attributes.lock().unwrap().clone();
         let evolver = EnrollmentsEvolver::new(
             &nimbus_id,
             &self.available_randomization_units,
-            &self.app_context,
+            &targeting_attributes,
         );
         let events = evolver.evolve_enrollments_in_db(&db, &mut writer, &existing_experiments)?;
         writer.commit()?;
         self.database_cache.update(&db)?;
+        self.notify_enrollment_observers(db, &events)?;
+        Ok(events)
+    }
+
+    pub fn get_preview_mode(&self) -> Result<bool> {
+        let db = self.db()?.lock().unwrap();
+        let writer = db.write()?;
+        get_preview_mode(&db, &writer)
+    }
+
+    /// Switches preview mode on or off. In preview mode, experiments are
+    /// fetched from the preview collection instead, and we enroll in them
+    /// regardless of bucketing, although targeting still applies.
+    ///
+    /// Switching preview mode off goes back to the experiments we had before
+    /// it was switched on, so we unenroll from any preview-only experiments.
+    /// Experiments we were only enrolled in because of preview mode are
+    /// re-bucketed, so we unenroll from those we aren't bucketed into.
+    /// In both cases, experiments which have been fetched but not applied yet
+    /// are discarded.
+    ///
+    /// Experiments read from a `file://` directory have no preview collection,
+    /// so the same directory is read in both modes.
+    pub fn set_preview_mode(&self, enabled: bool) -> Result<Vec<EnrollmentChangeEvent>> {
+        let db = self.db()?.lock().unwrap();
+        let mut writer = db.write()?;
+        if get_preview_mode(&db, &writer)? == enabled {
+            return Ok(vec![]);
+        }
+        set_preview_mode(&db, &mut writer, enabled)?;
+        read_and_remove_pending_experiments(&db, &mut writer)?;
+
+        let meta_store = db.get_store(StoreId::Meta);
+        let existing_experiments = db
+            .get_store(StoreId::Experiments)
+            .collect_all::<Experiment>(&writer)?;
+        let experiments = if enabled {
+            let experiments = existing_experiments;
+            meta_store.put(&mut writer, DB_KEY_EXPERIMENTS_BEFORE_PREVIEW, &experiments)?;
+            // Make sure we fetch the preview collection when it's next needed,
+            // even if it hasn't changed since the last time we were in preview mode.
+            reset_settings_client_etag(&db, &mut writer, DB_KEY_PREVIEW_SETTINGS_CLIENT_STATE)?;
+            experiments
+        } else {
+            let experiments: Option<Vec<Experiment>> =
+                meta_store.get(&writer, DB_KEY_EXPERIMENTS_BEFORE_PREVIEW)?;
+            match experiments {
+                Some(experiments) => {
+                    meta_store.delete(&mut writer, DB_KEY_EXPERIMENTS_BEFORE_PREVIEW)?;
+                    experiments
+                }
+                None => {
+                    // We don't know what we had before, so we keep the
+                    // current experiments until we next fetch.
+                    reset_settings_client_etag(&db, &mut writer, DB_KEY_SETTINGS_CLIENT_STATE)?;
+                    existing_experiments
+                }
+            }
+        };
+        // We evolve the enrollments against these experiments, so that
+        // switching on enrolls us in the ones we weren't bucketed into, and
+        // switching off unenrolls us from the preview-only experiments.
+        let nimbus_id = self.read_or_create_nimbus_id(&db, &mut writer)?;
+        let targeting_attributes = self.targeting_attributes.lock().unwrap().clone();
+        let evolver = EnrollmentsEvolver::new(
+            &nimbus_id,
+            &self.available_randomization_units,
+            &targeting_attributes,
+        );
+        let mut events = evolver.evolve_enrollments_in_db(&db, &mut writer, &experiments)?;
+        if !enabled {
+            events.extend(evolver.evolve_preview_enrollments_in_db(&db, &mut writer)?);
+        }
+        writer.commit()?;
+        self.database_cache.update(&db)?;
+        self.notify_enrollment_observers(db, &events)?;
         Ok(events)
     }
 
+    // The client to fetch experiments with, depending on whether we're in preview mode.
+    fn settings_client(&self, preview_mode: bool) -> &(dyn SettingsClient + Send + Sync) {
+        if preview_mode {
+            self.preview_settings_client.as_ref()
+        } else {
+            self.settings_client.as_ref()
+        }
+    }
+
     pub fn get_active_experiments(&self) -> Result<Vec<EnrolledExperiment>> {
         let db = self.db()?.lock().unwrap();
         get_enrollments(&db)
     }
 
+    pub fn get_active_rollouts(&self) -> Result<Vec<EnrolledExperiment>> {
+        let db = self.db()?.lock().unwrap();
+        get_rollout_enrollments(&db)
+    }
+
+    /// Returns the state of the user's enrollment in every experiment we know
+    /// about, including those they aren't enrolled in and why, eg for a debug
+    /// page to explain why the user isn't in an experiment.
+    pub fn get_enrollment_states(&self) -> Result<Vec<EnrollmentState>> {
+        let db = self.db()?.lock().unwrap();
+        get_enrollment_states(&db)
+    }
+
+    pub fn get_remote_metadata(&self) -> Result<RemoteMetadata> {
+        let (preview_mode, mut state) = self.read_settings_client_state()?;
+        let result = self
+            .settings_client(preview_mode)
+            .get_experiments_metadata_with_state(&mut state);
+        // We save the state even if the request failed, as the server may have
+        // asked us to back off.
+        let db = self.db()?.lock().unwrap();
+        let mut writer = db.write()?;
+        db.get_store(StoreId::Meta).put(
+            &mut writer,
+            settings_client_state_key(preview_mode),
+            &state,
+        )?;
+        writer.commit()?;
+        result
+    }
+
+    /// Returns the experiment records that were rejected when we last fetched
+    /// experiments (or they were set with `set_experiments_locally()`), eg
+    /// because they have an unsupported schema version or a malformed field,
+    /// so that apps can report broken experiments.
+    pub fn get_rejected_experiments(&self) -> Result<Vec<RejectedExperiment>> {
+        let db = self.db()?.lock().unwrap();
+        Ok(db
+            .get(StoreId::Meta, DB_KEY_REJECTED_EXPERIMENTS)?
+            .unwrap_or_default())
+    }
+
     pub fn get_all_experiments(&self) -> Result<Vec<Experiment>> {
         let db = self.db()?.lock().unwrap();
         db.collect_all(StoreId::Experiments)
@@ -141,8 +425,28 @@ impl NimbusClient {
         branch: String,
     ) -> Result<Vec<EnrollmentChangeEvent>> {
         let db = self.db()?.lock().unwrap();
-        let result = opt_in_with_branch(&db, &experiment_slug, &branch)?;
+        let result = match opt_in_with_branch(&db, &experiment_slug, &branch) {
+            Ok(result) => result,
+            Err(e) => {
+                // The observers hear about the failure too, eg so that it's
+                // recorded in telemetry, but if we can't tell them, we still
+                // return why opting in failed.
+                let event = EnrollmentChangeEvent::enroll_failed(
+                    &experiment_slug,
+                    Some(&branch),
+                    &e.to_string(),
+                );
+                if let Err(notify_error) = self.notify_enrollment_observers(db, &[event]) {
+                    log::warn!(
+                        "Failed to tell the enrollment observers that opting in failed: {}",
+                        notify_error
+                    );
+                }
+                return Err(e);
+            }
+        };
         self.database_cache.update(&db)?;
+        self.notify_enrollment_observers(db, &result)?;
         Ok(result)
     }
 
@@ -150,6 +454,7 @@ impl NimbusClient {
         let db = self.db()?.lock().unwrap();
         let result = opt_out(&db, &experiment_slug)?;
         self.database_cache.update(&db)?;
+        self.notify_enrollment_observers(db, &result)?;
         Ok(result)
     }
 
@@ -160,11 +465,63 @@ impl NimbusClient {
 
     pub fn fetch_experiments(&self) -> Result<()> {
         log::info!("fetching experiments");
-        let settings_client = self.settings_client.lock().unwrap();
-        let new_experiments = settings_client.fetch_experiments()?;
+        // We don't hold the db lock while we're on the network.
+        let (preview_mode, mut state) = self.read_settings_client_state()?;
+        let fetched = self
+            .settings_client(preview_mode)
+            .fetch_experiments_if_modified(&mut state);
         let db = self.db()?.lock().unwrap();
-        write_pending_experiments(&db, new_experiments)?;
-        Ok(())
+        let preview_mode_changed = get_preview_mode(&db, &db.write()?)? != preview_mode;
+        // Whether we have the server's experiments, either written now or
+        // unchanged since the last fetch.
+        let mut has_fetched = false;
+        let result = match fetched {
+            Ok(Some(_)) if preview_mode_changed => {
+                log::info!("preview mode changed while fetching; ignoring the experiments");
+                state.clear_etags();
+                Ok(())
+            }
+            Ok(Some(fetched)) => {
+                let result = write_fetched_experiments(&db, fetched);
+                if result.is_ok() {
+                    has_fetched = true;
+                } else {
+                    // Make sure we fetch these experiments again next time.
+                    state.clear_etags();
+                }
+                result
+            }
+            Ok(None) => {
+                log::info!("no new experiments to apply");
+                has_fetched = true;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        };
+        // We save the state even if the fetch failed, as the server may have
+        // asked us to back off.
+        let mut writer = db.write()?;
+        let meta_store = db.get_store(StoreId::Meta);
+        meta_store.put(&mut writer, settings_client_state_key(preview_mode), &state)?;
+        if has_fetched {
+            // We no longer need the bundled experiments.
+            meta_store.put(&mut writer, DB_KEY_HAS_FETCHED, &true)?;
+        }
+        writer.commit()?;
+        result
+    }
+
+    // Returns whether we're in preview mode, and the state of the settings
+    // client for that mode.
+    fn read_settings_client_state(&self) -> Result<(bool, SettingsClientState)> {
+        let db = self.db()?.lock().unwrap();
+        let writer = db.write()?;
+        let preview_mode = get_preview_mode(&db, &writer)?;
+        let state: SettingsClientState = db
+            .get_store(StoreId::Meta)
+            .get(&writer, settings_client_state_key(preview_mode))?
+            .unwrap_or_default();
+        Ok((preview_mode, state))
     }
 
     pub fn apply_pending_experiments(&self) -> Result<Vec<EnrollmentChangeEvent>> {
@@ -172,13 +529,14 @@ impl NimbusClient {
         let db = self.db()?.lock().unwrap();
         let mut writer = db.write()?;
         let pending_updates = read_and_remove_pending_experiments(&db, &mut writer)?;
-        Ok(match pending_updates {
+        let events = match pending_updates {
             Some(new_experiments) => {
                 let nimbus_id = self.read_or_create_nimbus_id(&db, &mut writer)?;
+                let targeting_attributes = self.targeting_attributes.lock().unwrap().clone();
                 let evolver = EnrollmentsEvolver::new(
                     &nimbus_id,
                     &self.available_randomization_units,
-                    &self.app_context,
+                    &targeting_attributes,
                 );
                 let events =
                     evolver.evolve_enrollments_in_db(&db, &mut writer, &new_experiments)?;
@@ -187,17 +545,114 @@ impl NimbusClient {
                 events
             }
             // We don't need to writer.commit() here because we haven't done anything.
-            None => vec![],
-        })
+            None => return Ok(vec![]),
+        };
+        self.notify_enrollment_observers(db, &events)?;
+        Ok(events)
+    }
+
+    /// Registers an observer to be told whenever the user's enrollments
+    /// change, eg by `apply_pending_experiments()`, `opt_out()` or
+    /// `set_global_user_participation()`, whoever called them. Observers are
+    /// kept for as long as the client.
+    pub fn register_enrollment_observer(&self, observer: Box<dyn EnrollmentObserver>) {
+        self.enrollment_observers.register(observer);
+    }
+
+    /// Registers a sink to record telemetry events for the user's enrollments
+    /// and exposures with, from then on. The bindings use an
+    /// `EnrollmentObserver` for this instead.
+    pub fn register_telemetry_sink(&self, sink: Box<dyn TelemetrySink>) {
+        self.register_enrollment_observer(Box::new(TelemetryObserver::new(sink)));
+    }
+
+    // Tells the enrollment observers about a committed change to the
+    // enrollments. We take the db lock so that we can release it before
+    // calling them, as they may call back into the client.
+    fn notify_enrollment_observers(
+        &self,
+        db: MutexGuard<'_, Database>,
+        events: &[EnrollmentChangeEvent],
+    ) -> Result<()> {
+        if events.is_empty() || self.enrollment_observers.is_empty() {
+            return Ok(());
+        }
+        let active_experiments = get_enrollments(&db)?;
+        drop(db);
+        self.enrollment_observers
+            .notify(events, &active_experiments);
+        Ok(())
+    }
+
+    /// Sets the custom attributes (eg, `is_first_run` or `days_since_install`)
+    /// that JEXL targeting expressions are evaluated against, replacing any
+    /// previously set. `attributes_json` must be a JSON-encoded object.
+    ///
+    /// The new attributes are used the next time enrollments are evaluated,
+    /// eg, by `apply_pending_experiments()`.
+    pub fn set_custom_targeting_attributes(&self, attributes_json: String) -> Result<()> {
+        let custom: Map<String, Value> = serde_json::from_str(&attributes_json)?;
+        self.targeting_attributes.lock().unwrap().custom = custom;
+        Ok(())
     }
 
     pub fn set_experiments_locally(&self, experiments_json: String) -> Result<()> {
-        let new_experiments = parse_experiments(&experiments_json)?;
+        let fetched = parse_experiments(&experiments_json)?;
         let db = self.db()?.lock().unwrap();
-        write_pending_experiments(&db, new_experiments)?;
+        write_fetched_experiments(&db, fetched)?;
+        // These experiments replace whatever we last fetched, so the next
+        // fetch must not be skipped as unmodified.
+        let mut writer = db.write()?;
+        let state_key = settings_client_state_key(get_preview_mode(&db, &writer)?);
+        reset_settings_client_etag(&db, &mut writer, state_key)?;
+        // We no longer need the bundled experiments.
+        db.get_store(StoreId::Meta)
+            .put(&mut writer, DB_KEY_HAS_FETCHED, &true)?;
+        writer.commit()?;
         Ok(())
     }
 
+    /// Watches the experiments for changes, when they are read from the
+    /// file-system (ie, `RemoteSettingsConfig.server_url` is a `file://` URL).
+    /// Whenever they change, they are written as a pending update, as if we
+    /// had fetched them, and then `callback` is called on a background thread
+    /// so that the app can apply them.
+    ///
+    /// This is for developing experiments locally. We keep watching until the
+    /// returned `ExperimentsWatcher` is dropped.
+    ///
+    /// The experiments watched are those for the current preview mode. If it's
+    /// switched, changes are ignored (with a warning) until the app watches again.
+    #[cfg(feature = "fs-watch")]
+    pub fn watch_experiments<F>(&self, callback: F) -> Result<ExperimentsWatcher>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let preview_mode = self.get_preview_mode()?;
+        let path = self
+            .settings_client(preview_mode)
+            .local_path()
+            .map(Path::to_path_buf)
+            .ok_or_else(|| {
+                Error::WatchError("the experiments aren't read from the file-system".to_string())
+            })?;
+        let db = Arc::clone(&self.db);
+        let db_path = self.db_path.clone();
+        client::watch_experiments(path, move |fetched| {
+            {
+                let db = open_db(&db, &db_path)?.lock().unwrap();
+                if get_preview_mode(&db, &db.write()?)? != preview_mode {
+                    return Err(Error::WatchError(
+                        "preview mode has changed since we started watching".to_string(),
+                    ));
+                }
+                write_fetched_experiments(&db, fetched)?;
+            }
+            callback();
+            Ok(())
+        })
+    }
+
     pub fn nimbus_id(&self) -> Result<Uuid> {
         let db = self.db()?.lock().unwrap();
         let mut writer = db.write()?;
@@ -234,9 +689,55 @@ impl NimbusClient {
     }
 
     fn db(&self) -> Result<&Mutex<Database>> {
-        self.db
-            .get_or_try_init(|| Ok(Mutex::new(Database::new(&self.db_path)?)))
+        open_db(&self.db, &self.db_path)
+    }
+}
+
+fn open_db<'a>(db: &'a OnceCell<Mutex<Database>>, db_path: &Path) -> Result<&'a Mutex<Database>> {
+    db.get_or_try_init(|| Ok(Mutex::new(Database::new(db_path)?)))
+}
+
+// Writes the experiments we fetched as the pending update, and keeps the
+// records we rejected for `get_rejected_experiments()`. Both are written in
+// one transaction, so that the rejected records always match the update.
+fn write_fetched_experiments(db: &Database, fetched: FetchedExperiments) -> Result<()> {
+    let mut writer = db.write()?;
+    write_pending_experiments(db, &mut writer, fetched.experiments)?;
+    db.get_store(StoreId::Meta)
+        .put(&mut writer, DB_KEY_REJECTED_EXPERIMENTS, &fetched.rejected)?;
+    writer.commit()?;
+    Ok(())
+}
+
+// Parses the experiments bundled with the app, which are either JSON, or the
+// path of a file of JSON.
+fn read_bundled_experiments(bundled_experiments: &str) -> Result<FetchedExperiments> {
+    if bundled_experiments.trim_start().starts_with('{') {
+        return parse_experiments(bundled_experiments);
+    }
+    let json = std::fs::read_to_string(bundled_experiments)
+        .map_err(|e| Error::InvalidPath(format!("{}: {}", bundled_experiments, e)))?;
+    parse_experiments(&json)
+}
+
+fn settings_client_state_key(preview_mode: bool) -> &'static str {
+    if preview_mode {
+        DB_KEY_PREVIEW_SETTINGS_CLIENT_STATE
+    } else {
+        DB_KEY_SETTINGS_CLIENT_STATE
+    }
+}
+
+// Forgets the `ETag`s in the persisted settings client state, so that the next
+// fetch isn't skipped as unmodified.
+fn reset_settings_client_etag(db: &Database, writer: &mut Writer, state_key: &str) -> Result<()> {
+    let store = db.get_store(StoreId::Meta);
+    let state: Option<SettingsClientState> = store.get(writer, state_key)?;
+    if let Some(mut state) = state {
+        state.clear_etags();
+        store.put(writer, state_key, &state)?;
     }
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
@@ -248,6 +749,27 @@ pub struct EnrolledExperiment {
     pub enrollment_id: String,
 }
 
+// The feature config of the branch of an experiment we are enrolled in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnrolledFeatureConfig {
+    pub feature_id: String,
+    pub experiment_slug: String,
+    pub branch_slug: String,
+    pub enabled: bool,
+    // The `value` of the `FeatureConfig`, JSON-encoded so it can cross the FFI.
+    pub value: Option<String>,
+}
+
+// The user was exposed to a feature (ie, they actually encountered it) which
+// is configured by the branch of an experiment they are enrolled in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExposureEvent {
+    pub feature_id: String,
+    pub experiment_slug: String,
+    pub branch_slug: String,
+    pub enrollment_id: String,
+}
+
 /// This is the currently supported major schema version.
 pub const SCHEMA_VERSION: u32 = 1;
 // XXX: In the future it would be nice if this lived in its own versioned crate so that
@@ -268,11 +790,23 @@ pub struct Experiment {
     pub probe_sets: Vec<String>,
     pub branches: Vec<Branch>,
     pub targeting: Option<String>,
-    pub start_date: Option<String>, // TODO: Use a date format here
-    pub end_date: Option<String>,   // TODO: Use a date format here
+    pub matcher: Option<Matcher>,
+    // Dates we can't parse are ignored, rather than rejecting the experiment.
+    #[serde(default, deserialize_with = "dates::deserialize_lenient")]
+    pub start_date: Option<ExperimentDate>,
+    #[serde(default, deserialize_with = "dates::deserialize_lenient")]
+    pub end_date: Option<ExperimentDate>,
     pub proposed_duration: Option<u32>,
     pub proposed_enrollment: u32,
     pub reference_branch: Option<String>,
+    // Rollouts are enrolled alongside experiments for the same feature, but
+    // the experiment's feature config takes precedence.
+    #[serde(default)]
+    pub is_rollout: bool,
+    // If set, users stay in their branch once enrolled, even if they stop
+    // matching the targeting, until the experiment ends or they opt out.
+    #[serde(default)]
+    pub sticky_enrollment: bool,
     // N.B. records in RemoteSettings will have `id` and `filter_expression` fields,
     // but we ignore them because they're for internal use by RemoteSettings.
 }
@@ -283,16 +817,27 @@ impl Experiment {
             .iter()
             .any(|branch| branch.slug == branch_slug)
     }
+
+    // The ids of all the features this experiment's branches configure.
+    fn get_feature_ids(&self) -> HashSet<String> {
+        self.branches
+            .iter()
+            .filter_map(|branch| branch.feature.as_ref())
+            .map(|feature| feature.feature_id.clone())
+            .collect()
+    }
 }
 
+// ⚠️ Warning : Altering this type might require a DB migration. ⚠️
 #[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct FeatureConfig {
     pub feature_id: String,
     pub enabled: bool,
-    // There is a nullable `value` field that can contain key-value config options
-    // that modify the behaviour of an application feature, but we don't support
-    // it yet and the details are still being finalized, so we ignore it for now.
+    // The nullable `value` field contains key-value config options that modify
+    // the behaviour of an application feature. Consumers get at these via
+    // `FeatureVariables`.
+    pub value: Option<Map<String, Value>>,
 }
 
 // ⚠️ Warning : Altering this type might require a DB migration. ⚠️
@@ -303,6 +848,24 @@ pub struct Branch {
     pub feature: Option<FeatureConfig>,
 }
 
+// The subset of a `Branch` we expose to consumers via the FFI. In particular,
+// uniffi can't express the arbitrary JSON of a `FeatureConfig`'s `value`, so
+// that is exposed separately via `get_feature_config_variables()`.
+#[derive(Debug, Clone)]
+pub struct ExperimentBranch {
+    pub slug: String,
+    pub ratio: u32,
+}
+
+impl From<Branch> for ExperimentBranch {
+    fn from(branch: Branch) -> Self {
+        Self {
+            slug: branch.slug,
+            ratio: branch.ratio,
+        }
+    }
+}
+
 fn default_buckets() -> u32 {
     DEFAULT_TOTAL_BUCKETS
 }
@@ -3,16 +3,26 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 //! This module defines all the information needed to match a user with an experiment.
-//! Soon it will also include a `match` function of some sort that does the matching.
 //!
 //! It has two main types, the `Matcher` retrieved from the server, and the `AppContext`
-//! provided by the consuming client.
+//! provided by the consuming client. `Matcher::find_mismatch` does the matching.
 //!
+//! JEXL targeting expressions are evaluated against the `TargetingAttributes`, which
+//! is the `AppContext` plus any custom attributes the consuming client provides.
+//!
+use crate::error::Result;
+use crate::versioning::compare_versions;
 use serde_derive::*;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
 
-#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+// Every field is optional: the matcher only constrains the fields the
+// experiment sets.
+// ⚠️ Warning : Altering this type might require a DB migration. ⚠️
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, rename_all = "camelCase")]
 pub struct Matcher {
-    pub app_id: String,
+    pub app_id: Option<String>,
     pub app_display_version: Option<String>,
     pub app_min_version: Option<String>,
     pub app_max_version: Option<String>,
@@ -29,6 +39,155 @@ pub struct Matcher {
     pub debug_tags: Vec<String>,
 }
 
+// The field of a `Matcher` that the `AppContext` didn't match.
+// ⚠️ Warning : Altering this type might require a DB migration. ⚠️
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Hash, Eq, PartialEq)]
+pub enum MatcherField {
+    AppId,
+    AppDisplayVersion,
+    AppMinVersion,
+    AppMaxVersion,
+    AppBuild,
+    AppMinBuild,
+    AppMaxBuild,
+    Architecture,
+    DeviceManufacturer,
+    DeviceModel,
+    Locale,
+    Os,
+    OsVersion,
+    AndroidSdkVersion,
+    DebugTags,
+}
+
+impl MatcherField {
+    /// The name of the field in the experiment's `matcher`, eg `appMinVersion`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MatcherField::AppId => "appId",
+            MatcherField::AppDisplayVersion => "appDisplayVersion",
+            MatcherField::AppMinVersion => "appMinVersion",
+            MatcherField::AppMaxVersion => "appMaxVersion",
+            MatcherField::AppBuild => "appBuild",
+            MatcherField::AppMinBuild => "appMinBuild",
+            MatcherField::AppMaxBuild => "appMaxBuild",
+            MatcherField::Architecture => "architecture",
+            MatcherField::DeviceManufacturer => "deviceManufacturer",
+            MatcherField::DeviceModel => "deviceModel",
+            MatcherField::Locale => "locale",
+            MatcherField::Os => "os",
+            MatcherField::OsVersion => "osVersion",
+            MatcherField::AndroidSdkVersion => "androidSdkVersion",
+            MatcherField::DebugTags => "debugTags",
+        }
+    }
+}
+
+impl Matcher {
+    /// Returns the first field of the matcher that the app context fails to
+    /// match, or `None` if the app context matches all of them.
+    ///
+    /// If the matcher sets a field that the app context doesn't provide, it
+    /// isn't a match.
+    pub fn find_mismatch(&self, app_context: &AppContext) -> Option<MatcherField> {
+        let app_version = app_context.app_version.as_deref();
+        let app_build = app_context.app_build.as_deref();
+        let checks = [
+            (
+                MatcherField::AppId,
+                equals(&self.app_id, Some(app_context.app_id.as_str())),
+            ),
+            (
+                MatcherField::AppDisplayVersion,
+                equals(&self.app_display_version, app_version),
+            ),
+            (
+                MatcherField::AppMinVersion,
+                at_least(&self.app_min_version, app_version),
+            ),
+            (
+                MatcherField::AppMaxVersion,
+                at_most(&self.app_max_version, app_version),
+            ),
+            (MatcherField::AppBuild, equals(&self.app_build, app_build)),
+            (
+                MatcherField::AppMinBuild,
+                at_least(&self.app_min_build, app_build),
+            ),
+            (
+                MatcherField::AppMaxBuild,
+                at_most(&self.app_max_build, app_build),
+            ),
+            (
+                MatcherField::Architecture,
+                equals(&self.architecture, app_context.architecture.as_deref()),
+            ),
+            (
+                MatcherField::DeviceManufacturer,
+                equals(
+                    &self.device_manufacturer,
+                    app_context.device_manufacturer.as_deref(),
+                ),
+            ),
+            (
+                MatcherField::DeviceModel,
+                equals(&self.device_model, app_context.device_model.as_deref()),
+            ),
+            (
+                MatcherField::Locale,
+                equals(&self.locale, app_context.locale.as_deref()),
+            ),
+            (
+                MatcherField::Os,
+                equals(&self.os, app_context.os.as_deref()),
+            ),
+            (
+                MatcherField::OsVersion,
+                equals(&self.os_version, app_context.os_version.as_deref()),
+            ),
+            (
+                MatcherField::AndroidSdkVersion,
+                equals(
+                    &self.android_sdk_version,
+                    app_context.android_sdk_version.as_deref(),
+                ),
+            ),
+            (
+                MatcherField::DebugTags,
+                self.debug_tags.is_empty()
+                    || matches!(&app_context.debug_tag, Some(tag) if self.debug_tags.contains(tag)),
+            ),
+        ];
+        checks
+            .iter()
+            .find(|(_, matched)| !matched)
+            .map(|(field, _)| *field)
+    }
+}
+
+fn equals(expected: &Option<String>, actual: Option<&str>) -> bool {
+    match expected {
+        None => true,
+        Some(expected) => actual == Some(expected.as_str()),
+    }
+}
+
+fn at_least(min: &Option<String>, actual: Option<&str>) -> bool {
+    match (min, actual) {
+        (None, _) => true,
+        (Some(min), Some(actual)) => compare_versions(actual, min) != Ordering::Less,
+        (Some(_), None) => false,
+    }
+}
+
+fn at_most(max: &Option<String>, actual: Option<&str>) -> bool {
+    match (max, actual) {
+        (None, _) => true,
+        (Some(max), Some(actual)) => compare_versions(actual, max) != Ordering::Greater,
+        (Some(_), None) => false,
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct AppContext {
     pub app_id: String,
@@ -43,3 +202,161 @@ pub struct AppContext {
     pub android_sdk_version: Option<String>,
     pub debug_tag: Option<String>,
 }
+
+/// Everything an experiment's targeting can be evaluated against.
+///
+/// The `custom` attributes are arbitrary values supplied by the application
+/// (eg, `is_first_run` or `days_since_install`). If a custom attribute has the
+/// same name as a field of the `AppContext`, the `AppContext` field wins.
+#[derive(Debug, Clone, Default)]
+pub struct TargetingAttributes {
+    pub app_context: AppContext,
+    pub custom: Map<String, Value>,
+}
+
+impl TargetingAttributes {
+    pub fn new(app_context: AppContext, custom: Map<String, Value>) -> Self {
+        Self {
+            app_context,
+            custom,
+        }
+    }
+
+    // The context JEXL expressions are evaluated in.
+    pub(crate) fn to_jexl_context(&self) -> Result<Value> {
+        let mut context = self.custom.clone();
+        if let Value::Object(app_context) = serde_json::to_value(&self.app_context)? {
+            context.extend(app_context);
+        }
+        Ok(Value::Object(context))
+    }
+}
+
+impl From<AppContext> for TargetingAttributes {
+    fn from(app_context: AppContext) -> Self {
+        Self::new(app_context, Map::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app_context() -> AppContext {
+        AppContext {
+            app_id: "org.mozilla.fenix".to_string(),
+            app_version: Some("85.0.1".to_string()),
+            app_build: Some("2015781043".to_string()),
+            locale: Some("en-US".to_string()),
+            os: Some("Android".to_string()),
+            debug_tag: Some("qa".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_empty_matcher_matches() {
+        assert_eq!(Matcher::default().find_mismatch(&app_context()), None);
+        assert_eq!(Matcher::default().find_mismatch(&Default::default()), None);
+    }
+
+    #[test]
+    fn test_matcher_fields() {
+        let matcher = Matcher {
+            app_id: Some("org.mozilla.fenix".to_string()),
+            app_min_version: Some("85.0".to_string()),
+            app_max_version: Some("86.*".to_string()),
+            app_min_build: Some("2015781000".to_string()),
+            locale: Some("en-US".to_string()),
+            os: Some("Android".to_string()),
+            debug_tags: vec!["qa".to_string(), "dev".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(matcher.find_mismatch(&app_context()), None);
+
+        let ctx = AppContext {
+            app_id: "org.mozilla.firefox_beta".to_string(),
+            ..app_context()
+        };
+        assert_eq!(matcher.find_mismatch(&ctx), Some(MatcherField::AppId));
+
+        let ctx = AppContext {
+            app_version: Some("85.0b2".to_string()),
+            ..app_context()
+        };
+        assert_eq!(
+            matcher.find_mismatch(&ctx),
+            Some(MatcherField::AppMinVersion)
+        );
+
+        let ctx = AppContext {
+            app_version: Some("100.0".to_string()),
+            ..app_context()
+        };
+        assert_eq!(
+            matcher.find_mismatch(&ctx),
+            Some(MatcherField::AppMaxVersion)
+        );
+
+        let ctx = AppContext {
+            locale: Some("de-DE".to_string()),
+            ..app_context()
+        };
+        assert_eq!(matcher.find_mismatch(&ctx), Some(MatcherField::Locale));
+
+        let ctx = AppContext {
+            debug_tag: None,
+            ..app_context()
+        };
+        assert_eq!(matcher.find_mismatch(&ctx), Some(MatcherField::DebugTags));
+    }
+
+    #[test]
+    fn test_matcher_missing_context() {
+        // The matcher needs a version, but the app didn't give us one.
+        let matcher = Matcher {
+            app_min_version: Some("85.0".to_string()),
+            ..Default::default()
+        };
+        let ctx = AppContext {
+            app_version: None,
+            ..app_context()
+        };
+        assert_eq!(
+            matcher.find_mismatch(&ctx),
+            Some(MatcherField::AppMinVersion)
+        );
+    }
+
+    #[test]
+    fn test_matcher_deserialize() {
+        let matcher: Matcher = serde_json::from_value(serde_json::json!({
+            "appMinVersion": "85.0",
+            "debugTags": ["qa"]
+        }))
+        .unwrap();
+        assert_eq!(matcher.app_min_version, Some("85.0".to_string()));
+        assert_eq!(matcher.debug_tags, vec!["qa".to_string()]);
+        assert_eq!(matcher.app_id, None);
+    }
+
+    #[test]
+    fn test_jexl_context() {
+        let custom = match serde_json::json!({
+            "is_first_run": true,
+            "days_since_install": 3,
+            "app_id": "not-the-app-id"
+        }) {
+            Value::Object(custom) => custom,
+            _ => unreachable!(),
+        };
+        let context = TargetingAttributes::new(app_context(), custom)
+            .to_jexl_context()
+            .unwrap();
+        assert_eq!(context["is_first_run"], true);
+        assert_eq!(context["days_since_install"], 3);
+        assert_eq!(context["locale"], "en-US");
+        // The app context takes precedence.
+        assert_eq!(context["app_id"], "org.mozilla.fenix");
+    }
+}
@@ -0,0 +1,68 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::enrollment::EnrollmentChangeEvent;
+use crate::{EnrolledExperiment, ExposureEvent};
+use std::sync::{Arc, Mutex};
+
+// This module lets components other than the caller (eg, the UI, telemetry or
+// feature modules) hear about changes to the user's enrollments, whichever
+// `NimbusClient` function made them, and about exposures to features.
+
+/// Something that wants to know whenever the user's enrollments change.
+///
+/// Observers are called after the change has been committed, on the thread
+/// that made it, and without any of the client's locks held, so they may call
+/// back into the `NimbusClient`.
+pub trait EnrollmentObserver: Send + Sync {
+    /// Called with the events for the change, and the experiments the user is
+    /// now enrolled in (not including rollouts, like `get_active_experiments()`).
+    /// When opting in fails, nothing changes, but this is called with an
+    /// `EnrollFailed` event.
+    fn on_enrollments_changed(
+        &self,
+        events: Vec<EnrollmentChangeEvent>,
+        active_experiments: Vec<EnrolledExperiment>,
+    );
+
+    /// Called when the user is first exposed to a feature under test in an
+    /// enrollment, by `NimbusClient::record_exposure()`.
+    fn on_exposure(&self, _event: ExposureEvent) {}
+}
+
+// The observers registered with a `NimbusClient`.
+#[derive(Default)]
+pub(crate) struct EnrollmentObservers {
+    // We hand out clones of the list when notifying, so that an observer can
+    // register another without deadlocking.
+    observers: Mutex<Vec<Arc<dyn EnrollmentObserver>>>,
+}
+
+impl EnrollmentObservers {
+    pub fn register(&self, observer: Box<dyn EnrollmentObserver>) {
+        self.observers.lock().unwrap().push(Arc::from(observer));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.lock().unwrap().is_empty()
+    }
+
+    pub fn notify(
+        &self,
+        events: &[EnrollmentChangeEvent],
+        active_experiments: &[EnrolledExperiment],
+    ) {
+        let observers = self.observers.lock().unwrap().clone();
+        for observer in observers {
+            observer.on_enrollments_changed(events.to_vec(), active_experiments.to_vec());
+        }
+    }
+
+    pub fn notify_exposure(&self, event: &ExposureEvent) {
+        let observers = self.observers.lock().unwrap().clone();
+        for observer in observers {
+            observer.on_exposure(event.clone());
+        }
+    }
+}
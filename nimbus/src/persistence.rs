@@ -84,7 +84,6 @@ impl SingleStore {
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn delete(&self, mut writer: &mut Writer, key: &str) -> Result<()> {
         self.store.delete(&mut writer, key)?;
         Ok(())
@@ -188,6 +187,9 @@ impl Database {
         }
         // It is safe to clear the update store (i.e. the pending experiments) on all schema upgrades
         // as it will be re-filled from the server on the next `fetch_experiments()`.
+        // Note that this relies on the settings client state (and its `ETag`) having been cleared
+        // with the meta store, otherwise that fetch will be skipped as unmodified; any future
+        // migration which keeps the meta store must delete it too.
         // The current contents of the update store may cause experiments to not load, or worse,
         // accidentally unenrol.
         self.updates_store.clear(&mut writer)?;
@@ -0,0 +1,263 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::enrollment::{DisqualifiedReason, EnrollmentChangeEvent, EnrollmentChangeEventType};
+use crate::error::Result;
+use crate::observer::EnrollmentObserver;
+use crate::{EnrolledExperiment, ExposureEvent};
+use serde_derive::*;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// This module reports enrollments and exposures as telemetry events, in the
+// shape of the Glean `nimbus_events` (ie, an event name and string extras).
+// The app provides a `TelemetrySink` to record them with; the sinks here are
+// for tests and desktop tooling.
+
+/// A telemetry event about the user's enrollment in an experiment.
+// The fields are named after the Glean event extras.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TelemetryEvent {
+    Enrollment {
+        experiment: String,
+        branch: String,
+        enrollment_id: String,
+    },
+    Unenrollment {
+        experiment: String,
+        branch: String,
+        enrollment_id: String,
+    },
+    /// `reason` is `"targeting"`, `"optout"` or `"error"`, from the `DisqualifiedReason`.
+    Disqualification {
+        experiment: String,
+        branch: String,
+        enrollment_id: String,
+        reason: String,
+    },
+    Exposure {
+        experiment: String,
+        branch: String,
+        enrollment_id: String,
+        feature_id: String,
+    },
+    /// We tried to enroll the user, but couldn't. `branch` is empty unless
+    /// they were opting in to one.
+    EnrollmentFailure {
+        experiment: String,
+        branch: String,
+        reason: String,
+    },
+}
+
+impl From<EnrollmentChangeEvent> for TelemetryEvent {
+    fn from(event: EnrollmentChangeEvent) -> Self {
+        let EnrollmentChangeEvent {
+            experiment_slug: experiment,
+            branch_slug: branch,
+            enrollment_id,
+            reason,
+            change,
+        } = event;
+        match change {
+            EnrollmentChangeEventType::Enrollment => Self::Enrollment {
+                experiment,
+                branch,
+                enrollment_id,
+            },
+            EnrollmentChangeEventType::Unenrollment => Self::Unenrollment {
+                experiment,
+                branch,
+                enrollment_id,
+            },
+            EnrollmentChangeEventType::Disqualification => Self::Disqualification {
+                experiment,
+                branch,
+                enrollment_id,
+                // Our disqualification events always have a `DisqualifiedReason`,
+                // but should one not, all we can say is that something went wrong.
+                reason: reason.unwrap_or_else(|| DisqualifiedReason::Error.as_str().to_owned()),
+            },
+            EnrollmentChangeEventType::EnrollFailed => Self::EnrollmentFailure {
+                experiment,
+                branch,
+                reason: reason.unwrap_or_default(),
+            },
+        }
+    }
+}
+
+impl From<ExposureEvent> for TelemetryEvent {
+    fn from(event: ExposureEvent) -> Self {
+        Self::Exposure {
+            experiment: event.experiment_slug,
+            branch: event.branch_slug,
+            enrollment_id: event.enrollment_id,
+            feature_id: event.feature_id,
+        }
+    }
+}
+
+/// Records telemetry events, eg by passing them to Glean.
+///
+/// Like an `EnrollmentObserver`, a sink is called on the thread that made the
+/// change, after it has been committed.
+pub trait TelemetrySink: Send + Sync {
+    fn record_event(&self, event: TelemetryEvent) -> Result<()>;
+}
+
+/// A `TelemetrySink` which keeps the events in memory, for tests. Clones
+/// share the same events, so a clone can be kept to inspect them.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTelemetrySink {
+    events: Arc<Mutex<Vec<TelemetryEvent>>>,
+}
+
+impl MemoryTelemetrySink {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the events recorded so far, oldest first.
+    pub fn events(&self) -> Vec<TelemetryEvent> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl TelemetrySink for MemoryTelemetrySink {
+    fn record_event(&self, event: TelemetryEvent) -> Result<()> {
+        self.events.lock().unwrap().push(event);
+        Ok(())
+    }
+}
+
+/// A `TelemetrySink` which appends the events to a file as JSON, one per
+/// line, for desktop tooling.
+pub struct JsonLinesTelemetrySink {
+    file: Mutex<File>,
+}
+
+impl JsonLinesTelemetrySink {
+    /// Opens the file at `path`, creating it if needed. Events are added after
+    /// any that are already there.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_ref())?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl TelemetrySink for JsonLinesTelemetrySink {
+    fn record_event(&self, event: TelemetryEvent) -> Result<()> {
+        let mut line = serde_json::to_string(&event)?;
+        line.push('\n');
+        // We write each line in one go, so that a reader never sees half an event.
+        self.file.lock().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+// Passes the enrollment changes and exposures a `NimbusClient` tells its
+// observers about to a `TelemetrySink`.
+pub(crate) struct TelemetryObserver {
+    sink: Box<dyn TelemetrySink>,
+}
+
+impl TelemetryObserver {
+    pub fn new(sink: Box<dyn TelemetrySink>) -> Self {
+        Self { sink }
+    }
+
+    fn record_event(&self, event: TelemetryEvent) {
+        // Failing to record telemetry shouldn't affect the enrollments.
+        if let Err(e) = self.sink.record_event(event) {
+            log::warn!("Failed to record a telemetry event: {}", e);
+        }
+    }
+}
+
+impl EnrollmentObserver for TelemetryObserver {
+    fn on_enrollments_changed(
+        &self,
+        events: Vec<EnrollmentChangeEvent>,
+        _active_experiments: Vec<EnrolledExperiment>,
+    ) {
+        for event in events {
+            self.record_event(event.into());
+        }
+    }
+
+    fn on_exposure(&self, event: ExposureEvent) {
+        self.record_event(event.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_json_lines_sink() -> Result<()> {
+        let tmp_dir = TempDir::new("test_json_lines_sink")?;
+        let path = tmp_dir.path().join("events.jsonl");
+        let enrollment = TelemetryEvent::Enrollment {
+            experiment: "secure-gold".to_string(),
+            branch: "control".to_string(),
+            enrollment_id: "1234".to_string(),
+        };
+        let disqualification = TelemetryEvent::Disqualification {
+            experiment: "secure-gold".to_string(),
+            branch: "control".to_string(),
+            enrollment_id: "1234".to_string(),
+            reason: "optout".to_string(),
+        };
+        JsonLinesTelemetrySink::new(&path)?.record_event(enrollment.clone())?;
+        // Events are appended to those already in the file.
+        JsonLinesTelemetrySink::new(&path)?.record_event(disqualification.clone())?;
+
+        let contents = std::fs::read_to_string(&path)?;
+        let lines: Vec<_> = contents.lines().collect();
+        assert_eq!(
+            lines[0],
+            r#"{"event":"enrollment","experiment":"secure-gold","branch":"control","enrollment_id":"1234"}"#
+        );
+        let events = lines
+            .into_iter()
+            .map(serde_json::from_str)
+            .collect::<std::result::Result<Vec<TelemetryEvent>, _>>()?;
+        assert_eq!(events, vec![enrollment, disqualification]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disqualification_reason() {
+        let enrollment_id = uuid::Uuid::new_v4();
+        let event = |reason| {
+            TelemetryEvent::from(EnrollmentChangeEvent::new(
+                "secure-gold",
+                &enrollment_id,
+                "control",
+                reason,
+                EnrollmentChangeEventType::Disqualification,
+            ))
+        };
+        let reason = |event: TelemetryEvent| match event {
+            TelemetryEvent::Disqualification { reason, .. } => reason,
+            event => panic!("unexpected event {:?}", event),
+        };
+        assert_eq!(
+            reason(event(Some(DisqualifiedReason::NotTargeted.as_str()))),
+            "targeting"
+        );
+        assert_eq!(reason(event(None)), "error");
+    }
+}
@@ -11,12 +11,20 @@ use crate::Experiment;
 
 const KEY_PENDING_UPDATES: &str = "pending-experiment-updates";
 
-pub fn write_pending_experiments(db: &Database, experiments: Vec<Experiment>) -> Result<()> {
-    let mut writer = db.write()?;
+pub fn write_pending_experiments(
+    db: &Database,
+    writer: &mut Writer,
+    experiments: Vec<Experiment>,
+) -> Result<()> {
     db.get_store(StoreId::Updates)
-        .put(&mut writer, KEY_PENDING_UPDATES, &experiments)?;
-    writer.commit()?;
-    Ok(())
+        .put(writer, KEY_PENDING_UPDATES, &experiments)
+}
+
+pub fn has_pending_experiments(db: &Database, writer: &Writer) -> Result<bool> {
+    let store = db.get_store(StoreId::Updates);
+    Ok(store
+        .get::<Vec<Experiment>>(writer, KEY_PENDING_UPDATES)?
+        .is_some())
 }
 
 pub fn read_and_remove_pending_experiments(
@@ -57,7 +65,9 @@ fn test_reading_writing_and_removing_experiments() -> Result<()> {
     // simulated fetch by constructing a dummy payload of 1 experiment.
     assert_eq!(fetched.len(), 1);
 
-    write_pending_experiments(&db, fetched)?;
+    let mut writer = db.write()?;
+    write_pending_experiments(&db, &mut writer, fetched)?;
+    writer.commit()?;
 
     // Now, we come to get the stashed updates, and they should be
     // the same.
@@ -0,0 +1,108 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Typed access to the key-value configuration (the `value` of a
+//! `FeatureConfig`) sent down with the branch of an experiment.
+//!
+//! The values are arbitrary JSON, so all the accessors return `None` when
+//! the key doesn't exist *or* when the value isn't of the requested type -
+//! consumers are expected to fall back to their default behaviour in that case.
+
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeatureVariables {
+    values: Map<String, Value>,
+}
+
+impl FeatureVariables {
+    pub fn new(values: Map<String, Value>) -> Self {
+        Self { values }
+    }
+
+    pub fn as_map(&self) -> &Map<String, Value> {
+        &self.values
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.values.contains_key(key)
+    }
+
+    pub fn get_string(&self, key: &str) -> Option<String> {
+        self.values.get(key)?.as_str().map(str::to_owned)
+    }
+
+    pub fn get_int(&self, key: &str) -> Option<i64> {
+        self.values.get(key)?.as_i64()
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.values.get(key)?.as_bool()
+    }
+
+    /// Returns the nested object at `key` as another set of variables.
+    pub fn get_variables(&self, key: &str) -> Option<FeatureVariables> {
+        self.values
+            .get(key)?
+            .as_object()
+            .map(|values| Self::new(values.clone()))
+    }
+}
+
+impl From<Map<String, Value>> for FeatureVariables {
+    fn from(values: Map<String, Value>) -> Self {
+        Self::new(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn test_variables() -> FeatureVariables {
+        match json!({
+            "title": "Hello",
+            "count": 3,
+            "enabled": true,
+            "nested": {
+                "color": "blue",
+                "depth": 2
+            }
+        }) {
+            Value::Object(values) => FeatureVariables::new(values),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_typed_accessors() {
+        let variables = test_variables();
+        assert_eq!(variables.get_string("title"), Some("Hello".to_string()));
+        assert_eq!(variables.get_int("count"), Some(3));
+        assert_eq!(variables.get_bool("enabled"), Some(true));
+        assert!(variables.contains_key("nested"));
+        assert!(!variables.contains_key("missing"));
+    }
+
+    #[test]
+    fn test_wrong_type_or_missing() {
+        let variables = test_variables();
+        assert_eq!(variables.get_string("count"), None);
+        assert_eq!(variables.get_int("title"), None);
+        assert_eq!(variables.get_bool("nested"), None);
+        assert_eq!(variables.get_variables("title"), None);
+        assert_eq!(variables.get_string("missing"), None);
+    }
+
+    #[test]
+    fn test_nested_variables() {
+        let nested = test_variables()
+            .get_variables("nested")
+            .expect("should be an object");
+        assert_eq!(nested.get_string("color"), Some("blue".to_string()));
+        assert_eq!(nested.get_int("depth"), Some(2));
+        assert_eq!(nested.get_variables("color"), None);
+    }
+}
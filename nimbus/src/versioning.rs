@@ -0,0 +1,208 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! This module implements version comparisons with the same semantics as
+//! Firefox's `nsIVersionComparator`, so that experiments can target ranges of
+//! application versions.
+//!
+//! A version is a list of parts separated by `.`, and each part has the
+//! form `<number-a><string-b><number-c><extra-d>`, eg `1`, `0b2` or `5pre1a`.
+//! Parts are compared field by field, where:
+//!
+//! - missing parts and numbers are treated as `0`,
+//! - a missing string sorts *after* any present string (so `1.0a1` < `1.0`),
+//! - a part of `*` is greater than any other part, and
+//! - a part with a `+` suffix is treated as a pre-release of the next version
+//!   (so `1.0+` == `1.1pre`).
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+
+#[derive(Debug, Default, PartialEq)]
+struct VersionPart {
+    num_a: i64,
+    str_b: String,
+    num_c: i64,
+    extra_d: String,
+}
+
+impl VersionPart {
+    fn parse(part: &str) -> Self {
+        if part == "*" {
+            return Self {
+                num_a: i64::MAX,
+                ..Default::default()
+            };
+        }
+        let (num_a, rest) = parse_number(part);
+        if rest.starts_with('+') {
+            return Self {
+                num_a: num_a.saturating_add(1),
+                str_b: "pre".to_string(),
+                ..Default::default()
+            };
+        }
+        let str_b_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '+' || c == '-')
+            .unwrap_or_else(|| rest.len());
+        let (str_b, rest) = rest.split_at(str_b_len);
+        let (num_c, extra_d) = parse_number(rest);
+        Self {
+            num_a,
+            str_b: str_b.to_string(),
+            num_c,
+            extra_d: extra_d.to_string(),
+        }
+    }
+
+    fn compare(&self, other: &Self) -> Ordering {
+        self.num_a
+            .cmp(&other.num_a)
+            .then_with(|| compare_strings(&self.str_b, &other.str_b))
+            .then_with(|| self.num_c.cmp(&other.num_c))
+            .then_with(|| compare_strings(&self.extra_d, &other.extra_d))
+    }
+}
+
+// Splits a leading (optionally negative) number off `s`, returning 0 if there
+// isn't one, similar to `strtol`.
+fn parse_number(s: &str) -> (i64, &str) {
+    let len = s
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || (i == 0 && c == '-')))
+        .map(|(i, _)| i)
+        .unwrap_or_else(|| s.len());
+    let (number, rest) = s.split_at(len);
+    (number.parse().unwrap_or(0), rest)
+}
+
+// An empty string is greater than a non-empty one.
+fn compare_strings(a: &str, b: &str) -> Ordering {
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.cmp(b),
+    }
+}
+
+/// Compares two version strings using Firefox's version semantics.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a_parts = a.split('.');
+    let mut b_parts = b.split('.');
+    loop {
+        let (a_part, b_part) = match (a_parts.next(), b_parts.next()) {
+            (None, None) => return Ordering::Equal,
+            (a_part, b_part) => (a_part.unwrap_or(""), b_part.unwrap_or("")),
+        };
+        match VersionPart::parse(a_part).compare(&VersionPart::parse(b_part)) {
+            Ordering::Equal => continue,
+            ordering => return ordering,
+        }
+    }
+}
+
+/// The `versionCompare` JEXL transform, eg `app_version|versionCompare('85.0') >= 0`.
+///
+/// Evaluates to a negative number if the subject is an older version than the
+/// argument, 0 if they are the same version, or a positive number if the
+/// subject is newer.
+pub fn version_compare_transform(args: &[Value]) -> Result<Value> {
+    let (a, b) = match args {
+        [a, b] => (a.as_str(), b.as_str()),
+        _ => {
+            return Err(anyhow!(
+                "versionCompare expects 1 argument, got {}",
+                args.len().saturating_sub(1)
+            ))
+        }
+    };
+    match (a, b) {
+        (Some(a), Some(b)) => Ok(json!(match compare_versions(a, b) {
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        })),
+        _ => Err(anyhow!("versionCompare can only compare strings")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_ordering() {
+        // Taken from the `nsIVersionComparator` documentation, in increasing order.
+        let versions = [
+            "1.0pre1",
+            "1.0pre2",
+            "1.0",
+            "1.0.1",
+            "1.1pre1a",
+            "1.1pre1",
+            "1.1pre10a",
+            "1.1pre10",
+            "1.1",
+            "1.10",
+            "2.0",
+            "*",
+        ];
+        for (i, a) in versions.iter().enumerate() {
+            for (j, b) in versions.iter().enumerate() {
+                assert_eq!(
+                    compare_versions(a, b),
+                    i.cmp(&j),
+                    "comparing {} with {}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_equivalent_versions() {
+        assert_eq!(compare_versions("1.0", "1.0.0"), Ordering::Equal);
+        assert_eq!(compare_versions("1.0", "1.0.0.0"), Ordering::Equal);
+        assert_eq!(compare_versions("1", "1.0"), Ordering::Equal);
+        assert_eq!(compare_versions("1.1pre", "1.1pre0"), Ordering::Equal);
+        assert_eq!(compare_versions("1.0+", "1.1pre"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_numeric_comparisons() {
+        // A naive string comparison gets these wrong.
+        assert_eq!(compare_versions("100.0", "99.0"), Ordering::Greater);
+        assert_eq!(compare_versions("85.0.1", "85.0"), Ordering::Greater);
+        assert_eq!(compare_versions("9.2", "10.0"), Ordering::Less);
+    }
+
+    #[test]
+    fn test_pre_release_versions() {
+        assert_eq!(compare_versions("85.0a1", "85.0b1"), Ordering::Less);
+        assert_eq!(compare_versions("85.0b1", "85.0b2"), Ordering::Less);
+        assert_eq!(compare_versions("85.0b2", "85.0"), Ordering::Less);
+        assert_eq!(compare_versions("85.0a1", "84.0"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_version_compare_transform() {
+        assert_eq!(
+            version_compare_transform(&[json!("100.0"), json!("99.0")]).unwrap(),
+            json!(1)
+        );
+        assert_eq!(
+            version_compare_transform(&[json!("85.0"), json!("85.0.0")]).unwrap(),
+            json!(0)
+        );
+        assert_eq!(
+            version_compare_transform(&[json!("85.0a1"), json!("85.0")]).unwrap(),
+            json!(-1)
+        );
+        assert!(version_compare_transform(&[json!("85.0")]).is_err());
+        assert!(version_compare_transform(&[Value::Null, json!("85.0")]).is_err());
+    }
+}
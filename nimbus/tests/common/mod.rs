@@ -20,84 +20,87 @@ pub fn new_test_client(identifier: &str) -> Result<NimbusClient> {
         server_url: url.as_str().to_string(),
         bucket_name: "doesn't matter".to_string(),
         collection_name: "doesn't matter".to_string(),
+        trust_root: None,
+        retry_policy: None,
+        additional_sources: vec![],
     };
     let aru = Default::default();
     let ctx = AppContext {
         app_id: "fenix".to_string(),
         ..Default::default()
     };
-    NimbusClient::new(ctx, tmp_dir.path(), Some(config), aru)
+    NimbusClient::new(ctx, tmp_dir.path(), Some(config), aru, None)
+}
+
+// The Remote Settings document listing the `experiments` records, as fetched
+// or passed to `set_experiments_locally()`.
+#[allow(dead_code)] // not clear why this is necessary...
+pub fn experiments_json(experiments: Vec<serde_json::Value>) -> String {
+    use serde_json::json;
+    json!({ "data": experiments }).to_string()
+}
+
+// The records of the initial experiments, `startup-gold` and `secure-gold`.
+#[allow(dead_code)] // not clear why this is necessary...
+pub fn initial_test_experiment_records() -> Vec<serde_json::Value> {
+    vec![
+        test_experiment("startup-gold"),
+        test_experiment("secure-gold"),
+    ]
 }
 
 #[allow(dead_code)] // not clear why this is necessary...
 pub fn initial_test_experiments() -> String {
+    experiments_json(initial_test_experiment_records())
+}
+
+// The initial experiments, with `startup-gold` configuring the `onboarding` feature.
+#[allow(dead_code)] // not clear why this is necessary...
+pub fn feature_test_experiments() -> String {
     use serde_json::json;
-    json!({
-        "data": [
-            {
-                "schemaVersion": "1.0.0",
-                "slug": "startup-gold",
-                "endDate": null,
-                "branches":[
-                    {"slug": "control", "ratio": 1},
-                    {"slug": "treatment","ratio":1}
-                ],
-                "probeSets":[],
-                "startDate":null,
-                "application":"fenix",
-                "bucketConfig":{
-                    // Setup to enroll everyone by default.
-                    "count":10_000,
-                    "start":0,
-                    "total":10_000,
-                    "namespace":"startup-gold",
-                    "randomizationUnit":"nimbus_id"
-                },
-                "userFacingName":"Diagnostic test experiment",
-                "referenceBranch":"control",
-                "isEnrollmentPaused":false,
-                "proposedEnrollment":7,
-                "userFacingDescription":"This is a test experiment for diagnostic purposes.",
-                "id":"secure-gold",
-                "last_modified":1_602_197_324_372i64
-            },
-            {
-                "schemaVersion": "1.0.0",
-                "slug": "secure-gold",
-                "endDate": null,
-                "branches":[
-                    {"slug": "control", "ratio": 1},
-                    {"slug": "treatment","ratio":1}
-                ],
-                "probeSets":[],
-                "startDate":null,
-                "application":"fenix",
-                "bucketConfig":{
-                    // Setup to enroll everyone by default.
-                    "count":10_000,
-                    "start":0,
-                    "total":10_000,
-                    "namespace":"secure-gold",
-                    "randomizationUnit":"nimbus_id"
-                },
-                "userFacingName":"Diagnostic test experiment",
-                "referenceBranch":"control",
-                "isEnrollmentPaused":false,
-                "proposedEnrollment":7,
-                "userFacingDescription":"This is a test experiment for diagnostic purposes.",
-                "id":"secure-gold",
-                "last_modified":1_602_197_324_372i64
-            }
-        ]
-    })
-    .to_string()
+    let mut experiments = initial_test_experiment_records();
+    for branch in experiments[0]["branches"].as_array_mut().unwrap() {
+        branch["feature"] = json!({"featureId": "onboarding", "enabled": true});
+    }
+    experiments_json(experiments)
 }
 
+// An experiment record which enrolls everyone in one of a `control` and a
+// `treatment` branch. Tests change the fields they're interested in, eg
+// `branches` or `targeting`.
 #[allow(dead_code)] // not clear why this is necessary...
-pub fn no_test_experiments() -> String {
+pub fn test_experiment(slug: &str) -> serde_json::Value {
     use serde_json::json;
     json!({
-        "data": []
+        "schemaVersion": "1.0.0",
+        "slug": slug,
+        "endDate": null,
+        "branches":[
+            {"slug": "control", "ratio": 1},
+            {"slug": "treatment","ratio":1}
+        ],
+        "probeSets":[],
+        "startDate":null,
+        "application":"fenix",
+        "bucketConfig":{
+            // Setup to enroll everyone by default.
+            "count":10_000,
+            "start":0,
+            "total":10_000,
+            "namespace":slug,
+            "randomizationUnit":"nimbus_id"
+        },
+        "userFacingName":"Diagnostic test experiment",
+        "referenceBranch":"control",
+        "isEnrollmentPaused":false,
+        "proposedEnrollment":7,
+        "userFacingDescription":"This is a test experiment for diagnostic purposes.",
+        "id":slug,
+        "last_modified":1_602_197_324_372i64
     })
-    .to_string()
+}
+
+#[allow(dead_code)] // not clear why this is necessary...
+pub fn no_test_experiments() -> String {
+    experiments_json(vec![])
 }
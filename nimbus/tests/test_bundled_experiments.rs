@@ -0,0 +1,136 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Testing the experiments bundled with the app for the first run.
+
+mod common;
+
+#[cfg(feature = "rkv-safe-mode")]
+#[cfg(test)]
+mod test {
+    use super::common::{initial_test_experiments, no_test_experiments};
+    use nimbus::{error::Result, AppContext, NimbusClient, RemoteSettingsConfig};
+    use std::path::{Path, PathBuf};
+    use tempdir::TempDir;
+    use url::Url;
+
+    fn new_client(db_path: &Path, bundled_experiments: String) -> Result<NimbusClient> {
+        let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        dir.push("tests/experiments");
+        let url = Url::from_file_path(dir).expect("experiments dir should exist");
+        let config = RemoteSettingsConfig {
+            server_url: url.as_str().to_string(),
+            bucket_name: "doesn't matter".to_string(),
+            collection_name: "doesn't matter".to_string(),
+            trust_root: None,
+            retry_policy: None,
+            additional_sources: vec![],
+        };
+        let ctx = AppContext {
+            app_id: "fenix".to_string(),
+            ..Default::default()
+        };
+        NimbusClient::new(
+            ctx,
+            db_path,
+            Some(config),
+            Default::default(),
+            Some(bundled_experiments),
+        )
+    }
+
+    fn active_slugs(client: &NimbusClient) -> Result<Vec<String>> {
+        let mut slugs: Vec<_> = client
+            .get_active_experiments()?
+            .into_iter()
+            .map(|e| e.slug)
+            .collect();
+        slugs.sort();
+        Ok(slugs)
+    }
+
+    #[test]
+    fn test_bundled_experiments() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_bundled_experiments")?;
+        let db_path = tmp_dir.path();
+        let bundle_path = tmp_dir.path().join("bundle.json");
+        std::fs::write(&bundle_path, initial_test_experiments())?;
+        let bundle = bundle_path.display().to_string();
+
+        // Before we've fetched, we enroll in the bundled experiments.
+        let client = new_client(db_path, bundle.clone())?;
+        client.initialize()?;
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold", "startup-gold"]);
+
+        // They aren't applied again on the next launch, so opting out sticks.
+        client.opt_out("startup-gold".to_string())?;
+        drop(client);
+        let client = new_client(db_path, bundle.clone())?;
+        client.initialize()?;
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold"]);
+
+        // The first fetch replaces them.
+        client.fetch_experiments()?;
+        client.apply_pending_experiments()?;
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold"]);
+
+        // And they aren't used again after that.
+        drop(client);
+        let client = new_client(db_path, bundle)?;
+        client.initialize()?;
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bundled_experiments_missing() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_bundled_experiments_missing")?;
+        let db_path = tmp_dir.path();
+        let bundle_path = tmp_dir.path().join("missing.json");
+
+        // A missing bundle doesn't stop us from working.
+        let client = new_client(db_path, bundle_path.display().to_string())?;
+        client.initialize()?;
+        assert!(active_slugs(&client)?.is_empty());
+        client.fetch_experiments()?;
+        client.apply_pending_experiments()?;
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bundled_experiments_json() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_bundled_experiments_json")?;
+
+        // The bundled experiments can be JSON rather than a path.
+        let client = new_client(tmp_dir.path(), initial_test_experiments())?;
+        client.initialize()?;
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold", "startup-gold"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bundled_experiments_after_set_locally() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_bundled_experiments_after_set_locally")?;
+        let db_path = tmp_dir.path();
+
+        // A pending update takes precedence over the bundled experiments.
+        let client = new_client(db_path, initial_test_experiments())?;
+        client.set_experiments_locally(no_test_experiments())?;
+        client.initialize()?;
+        assert!(active_slugs(&client)?.is_empty());
+
+        // And they aren't used once it's applied, either.
+        client.apply_pending_experiments()?;
+        drop(client);
+        let client = new_client(db_path, initial_test_experiments())?;
+        client.initialize()?;
+        assert!(active_slugs(&client)?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Testing targeting on custom attributes supplied by the application.
+
+mod common;
+
+#[cfg(feature = "rkv-safe-mode")]
+use nimbus::error::Result;
+
+#[cfg(feature = "rkv-safe-mode")]
+fn first_run_test_experiments() -> String {
+    use serde_json::json;
+    let mut experiment = common::test_experiment("first-run-gold");
+    experiment["targeting"] = json!("is_first_run && days_since_install < 7");
+    common::experiments_json(vec![experiment])
+}
+
+#[cfg(feature = "rkv-safe-mode")]
+#[test]
+fn test_custom_targeting_attributes() -> Result<()> {
+    let client = common::new_test_client("test_custom_targeting_attributes")?;
+    client.initialize()?;
+    client.set_custom_targeting_attributes(
+        r#"{"is_first_run": true, "days_since_install": 0}"#.to_string(),
+    )?;
+    client.set_experiments_locally(first_run_test_experiments())?;
+    let events = client.apply_pending_experiments()?;
+    assert_eq!(events.len(), 1);
+    assert!(client
+        .get_experiment_branch("first-run-gold".to_string())?
+        .is_some());
+    Ok(())
+}
+
+#[cfg(feature = "rkv-safe-mode")]
+#[test]
+fn test_custom_targeting_attributes_not_targeted() -> Result<()> {
+    let client = common::new_test_client("test_custom_targeting_attributes_not_targeted")?;
+    client.initialize()?;
+    client.set_custom_targeting_attributes(
+        r#"{"is_first_run": false, "days_since_install": 30}"#.to_string(),
+    )?;
+    client.set_experiments_locally(first_run_test_experiments())?;
+    let events = client.apply_pending_experiments()?;
+    assert!(events.is_empty());
+    assert!(client
+        .get_experiment_branch("first-run-gold".to_string())?
+        .is_none());
+    Ok(())
+}
+
+#[cfg(feature = "rkv-safe-mode")]
+#[test]
+fn test_custom_targeting_attributes_must_be_an_object() -> Result<()> {
+    let client = common::new_test_client("test_custom_targeting_attributes_must_be_an_object")?;
+    assert!(client
+        .set_custom_targeting_attributes("[1, 2, 3]".to_string())
+        .is_err());
+    assert!(client
+        .set_custom_targeting_attributes("{}".to_string())
+        .is_ok());
+    Ok(())
+}
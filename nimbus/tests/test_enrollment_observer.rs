@@ -0,0 +1,127 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Testing that enrollment observers hear about changes to the enrollments.
+
+mod common;
+
+#[cfg(feature = "rkv-safe-mode")]
+#[cfg(test)]
+mod test {
+    use super::common::{initial_test_experiments, new_test_client};
+    use nimbus::{
+        error::Result, EnrolledExperiment, EnrollmentChangeEvent, EnrollmentChangeEventType,
+        EnrollmentObserver, NimbusClient,
+    };
+    use std::sync::{Arc, Mutex};
+
+    type Changes = Arc<Mutex<Vec<(Vec<EnrollmentChangeEvent>, Vec<String>)>>>;
+
+    // Records the changes it's told about, with the slugs of the active experiments.
+    struct TestObserver {
+        changes: Changes,
+    }
+
+    impl EnrollmentObserver for TestObserver {
+        fn on_enrollments_changed(
+            &self,
+            events: Vec<EnrollmentChangeEvent>,
+            active_experiments: Vec<EnrolledExperiment>,
+        ) {
+            let mut slugs: Vec<_> = active_experiments.into_iter().map(|e| e.slug).collect();
+            slugs.sort();
+            self.changes.lock().unwrap().push((events, slugs));
+        }
+    }
+
+    fn new_observer() -> (Box<TestObserver>, Changes) {
+        let changes: Changes = Default::default();
+        let observer = TestObserver {
+            changes: Arc::clone(&changes),
+        };
+        (Box::new(observer), changes)
+    }
+
+    #[test]
+    fn test_enrollment_observer() -> Result<()> {
+        let client = new_test_client("test_enrollment_observer")?;
+        let (observer, changes) = new_observer();
+        client.register_enrollment_observer(observer);
+
+        client.set_experiments_locally(initial_test_experiments())?;
+        let events = client.apply_pending_experiments()?;
+        {
+            let changes = changes.lock().unwrap();
+            assert_eq!(changes.len(), 1);
+            assert_eq!(changes[0].0, events);
+            assert_eq!(changes[0].1, vec!["secure-gold", "startup-gold"]);
+        }
+
+        // Nothing changes, so we aren't told anything.
+        client.set_experiments_locally(initial_test_experiments())?;
+        client.apply_pending_experiments()?;
+        assert_eq!(changes.lock().unwrap().len(), 1);
+
+        client.opt_out("startup-gold".to_string())?;
+        {
+            let changes = changes.lock().unwrap();
+            assert_eq!(changes.len(), 2);
+            assert_eq!(changes[1].0.len(), 1);
+            assert_eq!(changes[1].0[0].experiment_slug, "startup-gold");
+            assert_eq!(
+                changes[1].0[0].change,
+                EnrollmentChangeEventType::Disqualification
+            );
+            assert_eq!(changes[1].1, vec!["secure-gold"]);
+        }
+
+        client.opt_in_with_branch("startup-gold".to_string(), "treatment".to_string())?;
+        client.set_global_user_participation(false)?;
+        let changes = changes.lock().unwrap();
+        assert_eq!(changes.len(), 4);
+        assert_eq!(
+            changes[2].0[0].change,
+            EnrollmentChangeEventType::Enrollment
+        );
+        assert_eq!(changes[2].1, vec!["secure-gold", "startup-gold"]);
+        assert_eq!(changes[3].0.len(), 2);
+        assert!(changes[3].1.is_empty());
+        Ok(())
+    }
+
+    // An observer which calls back into the client.
+    struct ReentrantObserver {
+        client: Arc<Mutex<Option<Arc<NimbusClient>>>>,
+        active: Arc<Mutex<Vec<EnrolledExperiment>>>,
+    }
+
+    impl EnrollmentObserver for ReentrantObserver {
+        fn on_enrollments_changed(
+            &self,
+            _events: Vec<EnrollmentChangeEvent>,
+            _active_experiments: Vec<EnrolledExperiment>,
+        ) {
+            let client = self.client.lock().unwrap().clone().unwrap();
+            *self.active.lock().unwrap() = client.get_active_experiments().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_enrollment_observer_can_use_client() -> Result<()> {
+        let client = Arc::new(new_test_client("test_enrollment_observer_can_use_client")?);
+        let client_cell = Arc::new(Mutex::new(Some(Arc::clone(&client))));
+        let active = Arc::new(Mutex::new(vec![]));
+        client.register_enrollment_observer(Box::new(ReentrantObserver {
+            client: Arc::clone(&client_cell),
+            active: Arc::clone(&active),
+        }));
+
+        client.set_experiments_locally(initial_test_experiments())?;
+        client.apply_pending_experiments()?;
+        assert_eq!(active.lock().unwrap().len(), 2);
+        // Break the cycle between the client and the observer.
+        client_cell.lock().unwrap().take();
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Testing recording exposures to features.
+
+mod common;
+
+#[cfg(feature = "rkv-safe-mode")]
+#[cfg(test)]
+mod test {
+    use super::common::{feature_test_experiments, new_test_client};
+    use nimbus::{
+        error::Result, EnrolledExperiment, EnrollmentChangeEvent, EnrollmentObserver, ExposureEvent,
+    };
+    use std::sync::{Arc, Mutex};
+
+    struct ExposureObserver {
+        exposures: Arc<Mutex<Vec<ExposureEvent>>>,
+    }
+
+    impl EnrollmentObserver for ExposureObserver {
+        fn on_enrollments_changed(
+            &self,
+            _events: Vec<EnrollmentChangeEvent>,
+            _active_experiments: Vec<EnrolledExperiment>,
+        ) {
+        }
+
+        fn on_exposure(&self, event: ExposureEvent) {
+            self.exposures.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn test_record_exposure() -> Result<()> {
+        let client = new_test_client("test_record_exposure")?;
+        let exposures = Arc::new(Mutex::new(vec![]));
+        client.register_enrollment_observer(Box::new(ExposureObserver {
+            exposures: Arc::clone(&exposures),
+        }));
+        client.set_experiments_locally(feature_test_experiments())?;
+        client.apply_pending_experiments()?;
+        let enrollment = client
+            .get_active_experiments()?
+            .into_iter()
+            .find(|e| e.slug == "startup-gold")
+            .unwrap();
+
+        client.record_exposure("onboarding".to_string())?;
+        assert_eq!(
+            *exposures.lock().unwrap(),
+            vec![ExposureEvent {
+                feature_id: "onboarding".to_string(),
+                experiment_slug: "startup-gold".to_string(),
+                branch_slug: enrollment.branch_slug,
+                enrollment_id: enrollment.enrollment_id,
+            }]
+        );
+
+        // We only record the first exposure for an enrollment, and none for
+        // features we aren't enrolled in an experiment for.
+        client.record_exposure("onboarding".to_string())?;
+        client.record_exposure("not-a-feature".to_string())?;
+        assert_eq!(exposures.lock().unwrap().len(), 1);
+
+        // A new enrollment is exposed again.
+        client.opt_out("startup-gold".to_string())?;
+        client.record_exposure("onboarding".to_string())?;
+        assert_eq!(exposures.lock().unwrap().len(), 1);
+        client.opt_in_with_branch("startup-gold".to_string(), "control".to_string())?;
+        client.record_exposure("onboarding".to_string())?;
+        let exposures = exposures.lock().unwrap();
+        assert_eq!(exposures.len(), 2);
+        assert_eq!(exposures[1].branch_slug, "control");
+        assert_ne!(exposures[1].enrollment_id, exposures[0].enrollment_id);
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Testing the feature config variables of the enrolled branch.
+
+mod common;
+
+#[cfg(feature = "rkv-safe-mode")]
+use nimbus::error::{Error, Result};
+
+// An experiment whose branches configure the `onboarding` feature, with a
+// `value` in the treatment branch.
+#[cfg(feature = "rkv-safe-mode")]
+fn onboarding_test_experiment() -> serde_json::Value {
+    use serde_json::json;
+    let mut experiment = common::test_experiment("onboarding-gold");
+    experiment["branches"] = json!([
+        {
+            "slug": "control",
+            "ratio": 1,
+            "feature": {
+                "featureId": "onboarding",
+                "enabled": false,
+                "value": null
+            }
+        },
+        {
+            "slug": "treatment",
+            "ratio": 1,
+            "feature": {
+                "featureId": "onboarding",
+                "enabled": true,
+                "value": {
+                    "title": "Welcome!",
+                    "cards": 3,
+                    "skippable": true,
+                    "theme": {"color": "purple"}
+                }
+            }
+        }
+    ]);
+    experiment
+}
+
+#[cfg(feature = "rkv-safe-mode")]
+fn onboarding_test_experiments() -> String {
+    common::experiments_json(vec![onboarding_test_experiment()])
+}
+
+#[cfg(feature = "rkv-safe-mode")]
+#[test]
+fn test_feature_variables() -> Result<()> {
+    let client = common::new_test_client("test_feature_variables")?;
+    client.initialize()?;
+    client.set_experiments_locally(onboarding_test_experiments())?;
+    client.apply_pending_experiments()?;
+
+    client.opt_in_with_branch("onboarding-gold".to_string(), "treatment".to_string())?;
+    let feature = client
+        .get_feature_config("onboarding".to_string())?
+        .expect("should be enrolled");
+    assert_eq!(feature.experiment_slug, "onboarding-gold");
+    assert_eq!(feature.branch_slug, "treatment");
+    assert!(feature.enabled);
+    let variables = client
+        .get_feature_variables("onboarding")?
+        .expect("should have variables");
+    assert_eq!(variables.get_string("title"), Some("Welcome!".to_string()));
+    assert_eq!(variables.get_int("cards"), Some(3));
+    assert_eq!(variables.get_bool("skippable"), Some(true));
+    let theme = variables.get_variables("theme").expect("should be nested");
+    assert_eq!(theme.get_string("color"), Some("purple".to_string()));
+
+    let json = client
+        .get_feature_config_variables("onboarding".to_string())?
+        .expect("should have variables");
+    let value: serde_json::Value = serde_json::from_str(&json)?;
+    assert_eq!(value["cards"], 3);
+
+    // No experiment configures this feature.
+    assert!(client.get_feature_variables("other-feature")?.is_none());
+
+    // The control branch has a null `value`.
+    client.opt_in_with_branch("onboarding-gold".to_string(), "control".to_string())?;
+    let feature = client
+        .get_feature_config("onboarding".to_string())?
+        .expect("should be enrolled");
+    assert_eq!(feature.branch_slug, "control");
+    assert!(!feature.enabled);
+    assert_eq!(feature.value, None);
+    assert!(client.get_feature_variables("onboarding")?.is_none());
+
+    // And when not enrolled at all.
+    client.opt_out("onboarding-gold".to_string())?;
+    assert!(client
+        .get_feature_config("onboarding".to_string())?
+        .is_none());
+    assert!(client
+        .get_feature_config_variables("onboarding".to_string())?
+        .is_none());
+    Ok(())
+}
+
+#[cfg(feature = "rkv-safe-mode")]
+#[test]
+fn test_feature_config_before_open() -> Result<()> {
+    let client = common::new_test_client("test_feature_config_before_open")?;
+    assert!(matches!(
+        client.get_feature_config("onboarding".to_string()),
+        Err(Error::DatabaseNotReady)
+    ));
+    client.initialize()?;
+    assert!(client
+        .get_feature_config("onboarding".to_string())?
+        .is_none());
+    Ok(())
+}
+
+#[cfg(feature = "rkv-safe-mode")]
+#[test]
+fn test_rollout_feature_config() -> Result<()> {
+    use serde_json::json;
+    // A rollout of the experiment's treatment branch to everyone.
+    let experiment = onboarding_test_experiment();
+    let mut rollout = experiment.clone();
+    rollout["slug"] = json!("onboarding-rollout");
+    rollout["id"] = json!("onboarding-rollout");
+    rollout["isRollout"] = json!(true);
+    rollout["bucketConfig"]["namespace"] = json!("onboarding-rollout");
+    let treatment = rollout["branches"][1].clone();
+    rollout["branches"] = json!([treatment]);
+    rollout["referenceBranch"] = json!(null);
+
+    let client = common::new_test_client("test_rollout_feature_config")?;
+    client.initialize()?;
+    client.set_experiments_locally(common::experiments_json(vec![experiment, rollout]))?;
+    client.apply_pending_experiments()?;
+
+    let active_experiments = client.get_active_experiments()?;
+    assert_eq!(active_experiments.len(), 1);
+    assert_eq!(active_experiments[0].slug, "onboarding-gold");
+    let active_rollouts = client.get_active_rollouts()?;
+    assert_eq!(active_rollouts.len(), 1);
+    assert_eq!(active_rollouts[0].slug, "onboarding-rollout");
+
+    // The experiment takes precedence over the rollout.
+    client.opt_in_with_branch("onboarding-gold".to_string(), "control".to_string())?;
+    let feature = client
+        .get_feature_config("onboarding".to_string())?
+        .expect("should be enrolled");
+    assert_eq!(feature.experiment_slug, "onboarding-gold");
+    assert!(!feature.enabled);
+
+    // Until we're no longer in the experiment.
+    client.opt_out("onboarding-gold".to_string())?;
+    let feature = client
+        .get_feature_config("onboarding".to_string())?
+        .expect("should be enrolled in the rollout");
+    assert_eq!(feature.experiment_slug, "onboarding-rollout");
+    assert!(feature.enabled);
+    let variables = client
+        .get_feature_variables("onboarding")?
+        .expect("should have variables");
+    assert_eq!(variables.get_int("cards"), Some(3));
+    Ok(())
+}
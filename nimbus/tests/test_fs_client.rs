@@ -28,12 +28,15 @@ fn test_simple() -> Result<()> {
         server_url: url.as_str().to_string(),
         bucket_name: "doesn't matter".to_string(),
         collection_name: "doesn't matter".to_string(),
+        trust_root: None,
+        retry_policy: None,
+        additional_sources: vec![],
     };
 
     let tmp_dir = TempDir::new("test_fs_client-test_simple")?;
 
     let aru = Default::default();
-    let client = NimbusClient::new(Default::default(), tmp_dir.path(), Some(config), aru)?;
+    let client = NimbusClient::new(Default::default(), tmp_dir.path(), Some(config), aru, None)?;
     client.fetch_experiments()?;
     client.apply_pending_experiments()?;
 
@@ -44,3 +47,41 @@ fn test_simple() -> Result<()> {
     // gets enrolled.
     Ok(())
 }
+
+#[cfg(feature = "rkv-safe-mode")]
+#[test]
+fn test_remote_metadata() -> Result<()> {
+    use nimbus::{NimbusClient, RemoteSettingsConfig};
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+    use url::Url;
+
+    let _ = env_logger::try_init();
+
+    let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    dir.push("tests/experiments");
+
+    let url = Url::from_file_path(&dir).expect("experiments dir should exist");
+
+    let config = RemoteSettingsConfig {
+        server_url: url.as_str().to_string(),
+        bucket_name: "doesn't matter".to_string(),
+        collection_name: "doesn't matter".to_string(),
+        trust_root: None,
+        retry_policy: None,
+        additional_sources: vec![],
+    };
+
+    let tmp_dir = TempDir::new("test_fs_client-test_remote_metadata")?;
+
+    let aru = Default::default();
+    let client = NimbusClient::new(Default::default(), tmp_dir.path(), Some(config), aru, None)?;
+    let metadata = client.get_remote_metadata()?;
+    assert_eq!(metadata.source, dir.display().to_string());
+    // The invalid experiment is counted, but not the README.
+    assert_eq!(metadata.record_count, 2);
+    assert!(metadata.last_modified.is_some());
+    assert_eq!(metadata.signature, None);
+    assert!(!metadata.signature_verification_enabled);
+    Ok(())
+}
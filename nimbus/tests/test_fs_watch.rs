@@ -0,0 +1,102 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Testing watching the file-system for changed experiments.
+
+mod common;
+
+#[cfg(all(feature = "rkv-safe-mode", feature = "fs-watch"))]
+#[cfg(test)]
+mod test {
+    use super::common::initial_test_experiments;
+    use nimbus::{error::Result, AppContext, NimbusClient, RemoteSettingsConfig};
+    use std::path::Path;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use tempdir::TempDir;
+    use url::Url;
+
+    fn new_client(db_path: &Path, experiments_path: &Path) -> Result<NimbusClient> {
+        let url = Url::from_file_path(experiments_path).expect("path should be absolute");
+        let config = RemoteSettingsConfig {
+            server_url: url.as_str().to_string(),
+            bucket_name: "doesn't matter".to_string(),
+            collection_name: "doesn't matter".to_string(),
+            trust_root: None,
+            retry_policy: None,
+            additional_sources: vec![],
+        };
+        let ctx = AppContext {
+            app_id: "fenix".to_string(),
+            ..Default::default()
+        };
+        NimbusClient::new(ctx, db_path, Some(config), Default::default(), None)
+    }
+
+    #[test]
+    fn test_watch_experiments() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_watch_experiments")?;
+        let experiments_dir = tmp_dir.path().join("experiments");
+        std::fs::create_dir(&experiments_dir)?;
+        let client = new_client(tmp_dir.path(), &experiments_dir)?;
+        client.fetch_experiments()?;
+        client.apply_pending_experiments()?;
+        assert!(client.get_active_experiments()?.is_empty());
+
+        let (tx, rx) = channel();
+        let watcher = client.watch_experiments(move || tx.send(()).unwrap())?;
+        let experiments_file = experiments_dir.join("experiments.json");
+        std::fs::write(&experiments_file, initial_test_experiments())?;
+        rx.recv_timeout(Duration::from_secs(10))
+            .expect("should be called back");
+        client.apply_pending_experiments()?;
+        assert_eq!(client.get_active_experiments()?.len(), 2);
+        // Wait for any more events for the same change.
+        while rx.recv_timeout(Duration::from_secs(1)).is_ok() {}
+
+        // Once the watcher is dropped, we no longer pick up changes.
+        drop(watcher);
+        std::fs::remove_file(&experiments_file)?;
+        assert!(rx.recv_timeout(Duration::from_secs(2)).is_err());
+        client.apply_pending_experiments()?;
+        assert_eq!(client.get_active_experiments()?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_experiments_preview_mode_changed() -> Result<()> {
+        let _ = env_logger::try_init();
+        let tmp_dir = TempDir::new("test_watch_experiments_preview_mode_changed")?;
+        let experiments_dir = tmp_dir.path().join("experiments");
+        std::fs::create_dir(&experiments_dir)?;
+        let client = new_client(tmp_dir.path(), &experiments_dir)?;
+
+        // Changes are ignored once preview mode is switched.
+        let (tx, rx) = channel();
+        let _watcher = client.watch_experiments(move || tx.send(()).unwrap())?;
+        client.set_preview_mode(true)?;
+        std::fs::write(
+            experiments_dir.join("experiments.json"),
+            initial_test_experiments(),
+        )?;
+        assert!(rx.recv_timeout(Duration::from_secs(2)).is_err());
+        assert!(client.apply_pending_experiments()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_experiments_not_on_file_system() -> Result<()> {
+        let tmp_dir = TempDir::new("test_watch_experiments_not_on_file_system")?;
+        let client = NimbusClient::new(
+            Default::default(),
+            tmp_dir.path(),
+            None,
+            Default::default(),
+            None,
+        )?;
+        assert!(client.watch_experiments(|| {}).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Testing switching preview mode on and off.
+
+mod common;
+
+#[cfg(feature = "rkv-safe-mode")]
+#[cfg(test)]
+mod test {
+    use super::common::{
+        experiments_json, initial_test_experiment_records, initial_test_experiments,
+        new_test_client, test_experiment,
+    };
+    use nimbus::{error::Result, EnrollmentChangeEventType, EnrollmentStateType, NimbusClient};
+
+    // The initial experiments, plus one that nobody is bucketed into.
+    fn preview_test_experiments() -> String {
+        let mut experiments = initial_test_experiment_records();
+        let mut preview = test_experiment("preview-only");
+        preview["bucketConfig"]["count"] = 0.into();
+        experiments.push(preview);
+        experiments_json(experiments)
+    }
+
+    fn active_slugs(client: &NimbusClient) -> Result<Vec<String>> {
+        let mut slugs: Vec<_> = client
+            .get_active_experiments()?
+            .into_iter()
+            .map(|e| e.slug)
+            .collect();
+        slugs.sort();
+        Ok(slugs)
+    }
+
+    #[test]
+    fn test_preview_mode() -> Result<()> {
+        let client = new_test_client("test_preview_mode")?;
+        client.set_experiments_locally(initial_test_experiments())?;
+        client.apply_pending_experiments()?;
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold", "startup-gold"]);
+        assert!(!client.get_preview_mode()?);
+
+        // We aren't bucketed into the preview experiment outside preview mode.
+        client.set_experiments_locally(preview_test_experiments())?;
+        client.apply_pending_experiments()?;
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold", "startup-gold"]);
+        client.set_experiments_locally(initial_test_experiments())?;
+        client.apply_pending_experiments()?;
+
+        let events = client.set_preview_mode(true)?;
+        assert!(events.is_empty());
+        assert!(client.get_preview_mode()?);
+
+        client.set_experiments_locally(preview_test_experiments())?;
+        let events = client.apply_pending_experiments()?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].experiment_slug, "preview-only");
+        assert_eq!(
+            active_slugs(&client)?,
+            vec!["preview-only", "secure-gold", "startup-gold"]
+        );
+
+        // Switching preview mode off unenrolls us from the preview experiment,
+        // and discards any pending preview experiments.
+        client.set_experiments_locally(preview_test_experiments())?;
+        let events = client.set_preview_mode(false)?;
+        assert!(!client.get_preview_mode()?);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].experiment_slug, "preview-only");
+        assert_eq!(events[0].reason, None);
+        assert!(client.apply_pending_experiments()?.is_empty());
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold", "startup-gold"]);
+        assert_eq!(client.get_all_experiments()?.len(), 2);
+
+        // Switching it off again does nothing.
+        assert!(client.set_preview_mode(false)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview_mode_rebuckets_enrollments() -> Result<()> {
+        // Nobody is bucketed into the experiment, but it's in both collections.
+        let client = new_test_client("test_preview_mode_rebuckets_enrollments")?;
+        client.set_experiments_locally(preview_test_experiments())?;
+        client.apply_pending_experiments()?;
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold", "startup-gold"]);
+
+        client.set_preview_mode(true)?;
+        client.set_experiments_locally(preview_test_experiments())?;
+        let events = client.apply_pending_experiments()?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].experiment_slug, "preview-only");
+        assert_eq!(
+            active_slugs(&client)?,
+            vec!["preview-only", "secure-gold", "startup-gold"]
+        );
+
+        // Switching preview mode off unenrolls us, as we aren't bucketed into
+        // it, but leaves the enrollments we had before alone.
+        let events = client.set_preview_mode(false)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].experiment_slug, "preview-only");
+        assert_eq!(events[0].change, EnrollmentChangeEventType::Unenrollment);
+        assert_eq!(events[0].reason.as_deref(), Some("preview"));
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold", "startup-gold"]);
+        assert_eq!(client.get_all_experiments()?.len(), 3);
+        // The stored enrollment matches the event.
+        let state = client
+            .get_enrollment_states()?
+            .into_iter()
+            .find(|state| state.experiment_slug == "preview-only")
+            .unwrap();
+        assert_eq!(state.state, EnrollmentStateType::WasEnrolled);
+        assert_eq!(state.enrollment_id, Some(events[0].enrollment_id.clone()));
+        assert_eq!(state.branch_slug, Some(events[0].branch_slug.clone()));
+
+        // We aren't enrolled again when we next apply the experiments.
+        client.set_experiments_locally(preview_test_experiments())?;
+        assert!(client.apply_pending_experiments()?.is_empty());
+        assert_eq!(active_slugs(&client)?, vec!["secure-gold", "startup-gold"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Testing the telemetry events recorded for enrollments and exposures.
+
+mod common;
+
+#[cfg(feature = "rkv-safe-mode")]
+#[cfg(test)]
+mod test {
+    use super::common::{
+        experiments_json, feature_test_experiments, initial_test_experiment_records,
+        new_test_client, no_test_experiments,
+    };
+    use nimbus::{error::Result, MemoryTelemetrySink, TelemetryEvent};
+
+    #[test]
+    fn test_telemetry_events() -> Result<()> {
+        let client = new_test_client("test_telemetry_events")?;
+        let sink = MemoryTelemetrySink::new();
+        client.register_telemetry_sink(Box::new(sink.clone()));
+
+        client.set_experiments_locally(feature_test_experiments())?;
+        client.apply_pending_experiments()?;
+        let enrollment = client
+            .get_active_experiments()?
+            .into_iter()
+            .find(|e| e.slug == "startup-gold")
+            .unwrap();
+        let events = sink.events();
+        assert_eq!(events.len(), 2);
+        assert!(events.contains(&TelemetryEvent::Enrollment {
+            experiment: "startup-gold".to_string(),
+            branch: enrollment.branch_slug.clone(),
+            enrollment_id: enrollment.enrollment_id.clone(),
+        }));
+
+        client.record_exposure("onboarding".to_string())?;
+        client.opt_out("startup-gold".to_string())?;
+        let events = sink.events();
+        assert_eq!(
+            events[2..],
+            [
+                TelemetryEvent::Exposure {
+                    experiment: "startup-gold".to_string(),
+                    branch: enrollment.branch_slug.clone(),
+                    enrollment_id: enrollment.enrollment_id.clone(),
+                    feature_id: "onboarding".to_string(),
+                },
+                TelemetryEvent::Disqualification {
+                    experiment: "startup-gold".to_string(),
+                    branch: enrollment.branch_slug,
+                    enrollment_id: enrollment.enrollment_id,
+                    reason: "optout".to_string(),
+                },
+            ]
+        );
+
+        // Experiments which are removed are unenrolled, even if we were
+        // disqualified from them.
+        client.set_experiments_locally(no_test_experiments())?;
+        client.apply_pending_experiments()?;
+        let mut unenrolled: Vec<_> = sink.events()[4..]
+            .iter()
+            .map(|event| match event {
+                TelemetryEvent::Unenrollment { experiment, .. } => experiment.clone(),
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect();
+        unenrolled.sort();
+        assert_eq!(unenrolled, vec!["secure-gold", "startup-gold"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_telemetry_enrollment_failures() -> Result<()> {
+        let client = new_test_client("test_telemetry_enrollment_failures")?;
+        let sink = MemoryTelemetrySink::new();
+        client.register_telemetry_sink(Box::new(sink.clone()));
+
+        // We can't bucket on a client id we don't have.
+        let mut experiments = initial_test_experiment_records();
+        experiments[0]["bucketConfig"]["randomizationUnit"] = "client_id".into();
+        client.set_experiments_locally(experiments_json(experiments))?;
+        client.apply_pending_experiments()?;
+        assert!(sink.events().contains(&TelemetryEvent::EnrollmentFailure {
+            experiment: "startup-gold".to_string(),
+            branch: "".to_string(),
+            reason: "No randomization unit".to_string(),
+        }));
+
+        // Opting in to a branch that doesn't exist fails, and is recorded.
+        assert!(client
+            .opt_in_with_branch("secure-gold".to_string(), "bobo".to_string())
+            .is_err());
+        match sink.events().last() {
+            Some(TelemetryEvent::EnrollmentFailure {
+                experiment, branch, ..
+            }) => {
+                assert_eq!(experiment, "secure-gold");
+                assert_eq!(branch, "bobo");
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        Ok(())
+    }
+}
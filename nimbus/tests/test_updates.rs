@@ -13,7 +13,8 @@ mod common;
 mod test {
     use super::common::{initial_test_experiments, new_test_client, no_test_experiments};
     #[cfg(feature = "rkv-safe-mode")]
-    use nimbus::{error::Result, NimbusClient};
+    use nimbus::{error::Result, NimbusClient, RejectionReason};
+    use serde_json::Value;
 
     fn startup(client: &NimbusClient, first_run: bool) -> Result<()> {
         if first_run {
@@ -83,6 +84,36 @@ mod test {
         Ok(())
     }
 
+    #[cfg(feature = "rkv-safe-mode")]
+    #[test]
+    fn test_rejected_experiments() -> Result<()> {
+        let client = new_test_client("test_rejected_experiments")?;
+        assert!(client.get_rejected_experiments()?.is_empty());
+
+        let mut experiments: Value = serde_json::from_str(&initial_test_experiments()).unwrap();
+        experiments["data"][0]["bucketConfig"]["randomizationUnit"] = "not_a_unit".into();
+        client.set_experiments_locally(experiments.to_string())?;
+        client.apply_pending_experiments()?;
+        assert_experiment_count(&client, 1)?;
+
+        let rejected = client.get_rejected_experiments()?;
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].slug.as_deref(), Some("startup-gold"));
+        assert_eq!(
+            rejected[0].reason,
+            RejectionReason::UnknownRandomizationUnit
+        );
+        assert_eq!(
+            rejected[0].field_path.as_deref(),
+            Some("bucketConfig.randomizationUnit")
+        );
+
+        // The next update replaces them.
+        client.set_experiments_locally(initial_test_experiments())?;
+        assert!(client.get_rejected_experiments()?.is_empty());
+        Ok(())
+    }
+
     #[cfg(feature = "rkv-safe-mode")]
     #[test]
     fn test_startup_behavior() -> Result<()> {